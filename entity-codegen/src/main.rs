@@ -0,0 +1,317 @@
+//! マイグレーション済みのDBから`webapi/src/db/`のSeaORMエンティティを生成する.
+//!
+//! ```sh
+//! cargo run -p entity-codegen -- generate [DB_URL]  # エンティティを書き出す
+//! cargo run -p entity-codegen -- check [DB_URL]     # 差分があれば失敗する
+//! ```
+//!
+//! エンティティのモジュール宣言は`webapi/src/db.rs`の目印のコメント直後に書き出す.
+//!
+//! `DB_URL`を省略した場合は環境変数`DB_URL`を使う. `DB_URL`のデータベースは変更しない.
+//! 同じサーバーに一時的なデータベースを作ってマイグレーションを適用し、そのスキーマから生成して削除する.
+//! そのため`DB_URL`のユーザーには`CREATEDB`の権限が必要.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, Stdio},
+};
+
+use migration::{Migrator, MigratorTrait};
+use sea_orm::SqlxPostgresConnector;
+use sea_orm_codegen::{DateTimeCrate, EntityTransformer, EntityWriterContext, WithSerde};
+use sea_schema::postgres::discovery::SchemaDiscovery;
+use sqlx::{postgres::PgConnectOptions, PgPool};
+
+/// 生成先. `mod.rs`は作らず、`db.rs`の`MODULES_MARKER`の直後にモジュール宣言を書く.
+const OUTPUT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../webapi/src/db");
+const DB_RS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../webapi/src/db.rs");
+const MODULES_MARKER: &str =
+    "// `cargo run -p entity-codegen -- generate`で生成する. 手で編集しないこと.\n";
+const SCHEMA: &str = "public";
+/// エンティティを作らないテーブル
+const IGNORED_TABLES: &[&str] = &["seaql_migrations"];
+const SKIPPED_FILES: &[&str] = &["mod.rs"];
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (command, url) = match args.as_slice() {
+        [command] => (command.as_str(), std::env::var("DB_URL").ok()),
+        [command, url] => (command.as_str(), Some(url.clone())),
+        _ => {
+            usage();
+            return ExitCode::FAILURE;
+        }
+    };
+    let Some(url) = url else {
+        eprintln!("DB_URLを指定してください");
+        return ExitCode::FAILURE;
+    };
+
+    let result = match command {
+        "generate" => generate(&url).await,
+        "check" => check(&url).await,
+        _ => {
+            eprintln!("不明なコマンド: {command}");
+            usage();
+            return ExitCode::FAILURE;
+        }
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn usage() {
+    eprintln!("usage: entity-codegen <generate|check> [DB_URL]");
+}
+
+async fn generate(url: &str) -> Result<bool, Box<dyn Error>> {
+    let files = render(url).await?;
+    let dir = Path::new(OUTPUT_DIR);
+    for (name, content) in &files {
+        fs::write(dir.join(name), content)?;
+        println!("wrote {name}");
+    }
+    for name in stale_files(dir, &files)? {
+        fs::remove_file(dir.join(&name))?;
+        println!("removed {name}");
+    }
+
+    let db_rs = fs::read_to_string(DB_RS)?;
+    let range = modules_range(&db_rs)?;
+    let modules = render_modules(&files);
+    if db_rs[range.clone()] != modules {
+        let mut updated = db_rs;
+        updated.replace_range(range, &modules);
+        fs::write(DB_RS, updated)?;
+        println!("wrote db.rs");
+    }
+    Ok(true)
+}
+
+/// コミット済みのエンティティが生成結果と一致するかを調べる.
+async fn check(url: &str) -> Result<bool, Box<dyn Error>> {
+    let files = render(url).await?;
+    let dir = Path::new(OUTPUT_DIR);
+    let mut drifted = Vec::new();
+    for (name, content) in &files {
+        match fs::read_to_string(dir.join(name)) {
+            Ok(current) if &current == content => {}
+            Ok(_) => drifted.push(format!("差分あり: {name}")),
+            Err(_) => drifted.push(format!("存在しない: {name}")),
+        }
+    }
+    for name in stale_files(dir, &files)? {
+        drifted.push(format!("対応するテーブルが無い: {name}"));
+    }
+    let db_rs = fs::read_to_string(DB_RS)?;
+    if db_rs[modules_range(&db_rs)?] != render_modules(&files) {
+        drifted.push("差分あり: db.rsのモジュール宣言".to_string());
+    }
+
+    if drifted.is_empty() {
+        println!("エンティティはスキーマと一致している");
+        return Ok(true);
+    }
+    for line in &drifted {
+        eprintln!("{line}");
+    }
+    eprintln!("`cargo run -p entity-codegen -- generate`で再生成してください");
+    Ok(false)
+}
+
+/// 一時的なデータベースにマイグレーションを適用してスキーマを読み、ファイル名と内容の組を返す.
+async fn render(url: &str) -> Result<BTreeMap<String, String>, Box<dyn Error>> {
+    let options: PgConnectOptions = url.parse()?;
+    let admin = PgPool::connect_with(options.clone()).await?;
+    let scratch = format!("entity_codegen_{}", std::process::id());
+    sqlx::query(&format!("CREATE DATABASE \"{scratch}\""))
+        .execute(&admin)
+        .await?;
+
+    let result = discover(options.database(&scratch)).await;
+
+    // 失敗した場合も一時的なデータベースは残さない
+    let dropped = sqlx::query(&format!("DROP DATABASE \"{scratch}\" WITH (FORCE)"))
+        .execute(&admin)
+        .await;
+    admin.close().await;
+    let schema = result?;
+    dropped?;
+
+    let tables = schema
+        .tables
+        .iter()
+        .filter(|table| !IGNORED_TABLES.contains(&table.info.name.as_str()))
+        .map(|table| table.write())
+        .collect();
+
+    let context = EntityWriterContext::new(
+        false,
+        WithSerde::None,
+        false,
+        DateTimeCrate::Chrono,
+        None,
+        false,
+        false,
+        false,
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        false,
+    );
+    let output = EntityTransformer::transform(tables)?.generate(&context);
+
+    output
+        .files
+        .into_iter()
+        .filter(|file| !SKIPPED_FILES.contains(&file.name.as_str()))
+        .map(|file| Ok((file.name, rustfmt(&file.content)?)))
+        .collect()
+}
+
+/// マイグレーションを全て適用したスキーマ
+async fn discover(
+    options: PgConnectOptions,
+) -> Result<sea_schema::postgres::def::Schema, Box<dyn Error>> {
+    let pool = PgPool::connect_with(options).await?;
+    let db = SqlxPostgresConnector::from_sqlx_postgres_pool(pool.clone());
+    Migrator::up(&db, None).await?;
+    let schema = SchemaDiscovery::new(pool.clone(), SCHEMA)
+        .discover()
+        .await?;
+    pool.close().await;
+    Ok(schema)
+}
+
+/// 生成先にあるが生成結果に含まれないファイル
+fn stale_files(dir: &Path, files: &BTreeMap<String, String>) -> std::io::Result<Vec<String>> {
+    let mut stale = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path: PathBuf = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if path.extension().is_some_and(|ext| ext == "rs")
+            && !files.contains_key(name)
+            && !SKIPPED_FILES.contains(&name)
+        {
+            stale.push(name.to_string());
+        }
+    }
+    stale.sort();
+    Ok(stale)
+}
+
+/// `db.rs`に書くモジュール宣言. `rustfmt`と同じくアルファベット順に並べる.
+fn render_modules(files: &BTreeMap<String, String>) -> String {
+    files
+        .keys()
+        .filter_map(|name| name.strip_suffix(".rs"))
+        .map(|module| format!("pub mod {module};\n"))
+        .collect()
+}
+
+/// `db.rs`のうち`MODULES_MARKER`の直後に続く`pub mod`の行の範囲
+fn modules_range(db_rs: &str) -> Result<std::ops::Range<usize>, Box<dyn Error>> {
+    let start = db_rs
+        .find(MODULES_MARKER)
+        .ok_or("db.rsにモジュール宣言の目印のコメントが無い")?
+        + MODULES_MARKER.len();
+    let len = db_rs[start..]
+        .split_inclusive('\n')
+        .take_while(|line| line.starts_with("pub mod "))
+        .map(str::len)
+        .sum::<usize>();
+    Ok(start..start + len)
+}
+
+/// `cargo fmt`後の内容と比較できるよう、リポジトリと同じ設定で整形する.
+fn rustfmt(source: &str) -> Result<String, Box<dyn Error>> {
+    let mut child = Command::new("rustfmt")
+        .args(["--edition", "2021"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .expect("stdinはpipedにしている")
+        .write_all(source.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err("rustfmtに失敗した".into());
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `DB_URL=... cargo test -p entity-codegen -- --ignored`で実行する.
+    #[tokio::test]
+    #[ignore = "DB_URLのPostgreSQLが必要"]
+    async fn entities_match_migrations() {
+        let url = std::env::var("DB_URL").expect("DB_URLを指定してください");
+        assert!(
+            check(&url).await.unwrap(),
+            "エンティティを再生成してください"
+        );
+    }
+
+    #[test]
+    fn finds_stale_files() {
+        let dir = std::env::temp_dir().join(format!("entity-codegen-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in ["users.rs", "removed.rs", "mod.rs", "README.md"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let files = BTreeMap::from([("users.rs".to_string(), String::new())]);
+
+        let stale = stale_files(&dir, &files).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(stale, ["removed.rs"]);
+    }
+
+    #[test]
+    fn finds_module_declarations() {
+        let db_rs =
+            format!("use a;\n\n{MODULES_MARKER}pub mod users;\npub mod prelude;\n\nstruct A;\n");
+        let range = modules_range(&db_rs).unwrap();
+        assert_eq!(&db_rs[range], "pub mod users;\npub mod prelude;\n");
+        assert!(modules_range("pub mod users;\n").is_err());
+
+        let files = BTreeMap::from([
+            ("prelude.rs".to_string(), String::new()),
+            ("users.rs".to_string(), String::new()),
+        ]);
+        assert_eq!(render_modules(&files), "pub mod prelude;\npub mod users;\n");
+    }
+
+    #[test]
+    fn committed_module_declarations_match_entity_files() {
+        let files = fs::read_dir(OUTPUT_DIR)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".rs") && !SKIPPED_FILES.contains(&name.as_str()))
+            .map(|name| (name, String::new()))
+            .collect();
+        let db_rs = fs::read_to_string(DB_RS).unwrap();
+        assert_eq!(
+            db_rs[modules_range(&db_rs).unwrap()],
+            render_modules(&files)
+        );
+    }
+}
@@ -0,0 +1,17 @@
+pub use sea_orm_migration::prelude::*;
+
+mod m20240501_000001_create_openid_connect_states;
+mod m20240601_000001_create_example_notes;
+
+/// スキーマの変更履歴. 新しいマイグレーションは末尾に追加する. 適用済みのものは書き換えない.
+pub struct Migrator;
+
+#[async_trait::async_trait]
+impl MigratorTrait for Migrator {
+    fn migrations() -> Vec<Box<dyn MigrationTrait>> {
+        vec![
+            Box::new(m20240501_000001_create_openid_connect_states::Migration),
+            Box::new(m20240601_000001_create_example_notes::Migration),
+        ]
+    }
+}
@@ -0,0 +1,42 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(OpenidConnectStates::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(OpenidConnectStates::Sid)
+                            .string_len(26)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(OpenidConnectStates::State)
+                            .string_len(26)
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(OpenidConnectStates::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum OpenidConnectStates {
+    Table,
+    Sid,
+    State,
+}
@@ -0,0 +1,45 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ExampleNotes::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ExampleNotes::Id)
+                            .string_len(26)
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ExampleNotes::Body).text().not_null())
+                    // 楽観的排他制御に使う. 更新のたびに新しいULIDにする
+                    .col(
+                        ColumnDef::new(ExampleNotes::Version)
+                            .string_len(26)
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(ExampleNotes::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(DeriveIden)]
+enum ExampleNotes {
+    Table,
+    Id,
+    Body,
+    Version,
+}
@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitInt, LitStr};
+
+/// `webapi::framework::system`が予約しているエラーコード. `AppError`の組み込みバリアントと重複させない.
+const RESERVED_CODES: [&str; 4] = [
+    "unexpected_error",
+    "authentication_error",
+    "authorization_error",
+    "workflow_exception",
+];
+
+/// ドメインエラーのenumから`IntoAppError`と`DomainError`を実装する.
+///
+/// ```ignore
+/// #[derive(Debug, DomainError)]
+/// enum TokenError {
+///     #[domain_error(code = "oidc.invalid_grant", status = 400)]
+///     InvalidGrant(String),
+///     #[domain_error(code = "oidc.invalid_client", status = 401, message_key = "error.oidc.client")]
+///     InvalidClient,
+///     #[domain_error(panic)]
+///     Unexpected(Panic),
+/// }
+/// ```
+///
+/// - `code`: 機械可読なエラーコード. enum内や組み込みのコードと重複するとコンパイルエラー.
+///   他の型との重複は`system::duplicate_error_codes`で起動時に検出する.
+/// - `status`: HTTPステータス.
+/// - `message_key`: 翻訳メッセージのキー. 省略すると`error.{code}`.
+/// - `panic`: `Panic`を1つだけ持つバリアント. `from_panic`の変換先になり`AppError::Unexpected`になる.
+///
+/// `panic`以外のバリアントは`Display`の結果が`detail`になるので、`Display`の実装が必要.
+#[proc_macro_derive(DomainError, attributes(domain_error))]
+pub fn derive_domain_error(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+struct CodedVariant {
+    ident: Ident,
+    fields: Fields,
+    code: LitStr,
+    status: LitInt,
+    message_key: LitStr,
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "DomainErrorはenumにのみderiveできる",
+        ));
+    };
+
+    let mut panic_variant: Option<Ident> = None;
+    let mut coded = Vec::new();
+    let mut seen = HashSet::new();
+
+    for variant in &data.variants {
+        let mut is_panic = false;
+        let mut code: Option<LitStr> = None;
+        let mut status: Option<LitInt> = None;
+        let mut message_key: Option<LitStr> = None;
+
+        for attr in variant
+            .attrs
+            .iter()
+            .filter(|a| a.path().is_ident("domain_error"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("panic") {
+                    is_panic = true;
+                } else if meta.path.is_ident("code") {
+                    code = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("status") {
+                    status = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("message_key") {
+                    message_key = Some(meta.value()?.parse()?);
+                } else {
+                    return Err(meta.error(
+                        "domain_errorに指定できるのはcode, status, message_key, panicのみ",
+                    ));
+                }
+                Ok(())
+            })?;
+        }
+
+        if is_panic {
+            if code.is_some() || status.is_some() || message_key.is_some() {
+                return Err(syn::Error::new_spanned(
+                    &variant.ident,
+                    "panicのバリアントにはcode, status, message_keyを指定できない",
+                ));
+            }
+            if !matches!(&variant.fields, Fields::Unnamed(f) if f.unnamed.len() == 1) {
+                return Err(syn::Error::new_spanned(
+                    &variant.ident,
+                    "panicのバリアントは`Variant(Panic)`の形である必要がある",
+                ));
+            }
+            if panic_variant.is_some() {
+                return Err(syn::Error::new_spanned(
+                    &variant.ident,
+                    "panicのバリアントは1つだけ",
+                ));
+            }
+            panic_variant = Some(variant.ident.clone());
+            continue;
+        }
+
+        let code = code.ok_or_else(|| {
+            syn::Error::new_spanned(&variant.ident, "#[domain_error(code = \"...\")]が必要")
+        })?;
+        let status = status.ok_or_else(|| {
+            syn::Error::new_spanned(&variant.ident, "#[domain_error(status = ...)]が必要")
+        })?;
+
+        let code_value = code.value();
+        if code_value.is_empty() {
+            return Err(syn::Error::new_spanned(&code, "codeは空にできない"));
+        }
+        if RESERVED_CODES.contains(&code_value.as_str()) {
+            return Err(syn::Error::new_spanned(
+                &code,
+                format!("`{code_value}`はAppErrorが予約しているエラーコード"),
+            ));
+        }
+        if !seen.insert(code_value.clone()) {
+            return Err(syn::Error::new_spanned(
+                &code,
+                format!("エラーコード`{code_value}`が重複している"),
+            ));
+        }
+
+        let status_value: u16 = status.base10_parse()?;
+        if !(400..=599).contains(&status_value) {
+            return Err(syn::Error::new_spanned(
+                &status,
+                "statusは400から599の範囲で指定する",
+            ));
+        }
+
+        let message_key =
+            message_key.unwrap_or_else(|| LitStr::new(&format!("error.{code_value}"), code.span()));
+
+        coded.push(CodedVariant {
+            ident: variant.ident.clone(),
+            fields: variant.fields.clone(),
+            code,
+            status,
+            message_key,
+        });
+    }
+
+    let panic_variant = panic_variant.ok_or_else(|| {
+        syn::Error::new_spanned(
+            &input.ident,
+            "#[domain_error(panic)]を付けた`Variant(Panic)`のバリアントが1つ必要",
+        )
+    })?;
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let system = quote!(::webapi::framework::system);
+
+    let code_arms = coded.iter().map(|v| {
+        let CodedVariant {
+            ident,
+            fields,
+            code,
+            status,
+            message_key,
+        } = v;
+        let pattern = match fields {
+            Fields::Named(_) => quote!(Self::#ident { .. }),
+            Fields::Unnamed(_) => quote!(Self::#ident(..)),
+            Fields::Unit => quote!(Self::#ident),
+        };
+        quote! {
+            #pattern => #system::ErrorCode::new(#code, #status, #message_key),
+        }
+    });
+
+    let registrations = coded.iter().map(|v| {
+        let CodedVariant {
+            ident,
+            code,
+            status,
+            message_key,
+            ..
+        } = v;
+        quote! {
+            #system::inventory::submit! {
+                #system::RegisteredErrorCode {
+                    error_code: #system::ErrorCode::new(#code, #status, #message_key),
+                    defined_at: ::std::concat!(
+                        ::std::module_path!(), "::", ::std::stringify!(#name), "::", ::std::stringify!(#ident)
+                    ),
+                }
+            }
+        }
+    });
+
+    Ok(quote! {
+        #(#registrations)*
+
+        impl #impl_generics #system::IntoAppError for #name #ty_generics #where_clause {
+            fn into_app_error(
+                self,
+                l: ::webapi::framework::logger::Logger,
+                req_id: &::ulid::Ulid,
+            ) -> #system::AppError {
+                match self {
+                    Self::#panic_variant(e) => #system::IntoAppError::into_app_error(e, l, req_id),
+                    other => {
+                        let code = #system::DomainError::error_code(&other);
+                        #system::AppError::DomainException(code, ::std::string::ToString::to_string(&other))
+                    }
+                }
+            }
+        }
+
+        impl #impl_generics #system::DomainError for #name #ty_generics #where_clause {
+            fn from_panic(e: #system::Panic) -> Self {
+                Self::#panic_variant(e)
+            }
+
+            fn error_code(&self) -> #system::ErrorCode {
+                match self {
+                    Self::#panic_variant(..) => #system::ErrorCode::UNEXPECTED,
+                    #(#code_arms)*
+                }
+            }
+        }
+    })
+}
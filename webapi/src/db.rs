@@ -1,20 +1,603 @@
-use std::time::Duration;
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+};
 
-use sea_orm::{ConnectOptions, Database, DatabaseConnection};
+use axum::{
+    async_trait, extract,
+    http::{request::Parts, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+};
+use migration::{Migrator, MigratorTrait};
+use sea_orm::{
+    ConnectOptions, ConnectionTrait, Database, DatabaseConnection, DatabaseTransaction, DbErr,
+    Statement, TransactionTrait,
+};
+use tokio::{sync::OnceCell, time::MissedTickBehavior};
+use ulid::Ulid;
+use url::Url;
 
+use crate::framework::{
+    env::Env,
+    logger::Logger,
+    problem::ProblemDetails,
+    system::{AppError, ErrorCode, IntoAppError, Panic},
+    AppState, ReqScopedState,
+};
+
+// `cargo run -p entity-codegen -- generate`で生成する. 手で編集しないこと.
+pub mod example_notes;
 pub mod openid_connect_states;
+pub mod prelude;
+
+/// 書き込みとトランザクションはprimaryへ、読み取り専用のクエリはreplicaへ振り分ける.
+/// replicaが未設定か不調な間はprimaryから読む.
+#[derive(Clone, Debug)]
+pub struct DbHandle {
+    primary: DatabaseConnection,
+    replica: Option<Arc<Replica>>,
+}
+
+#[derive(Debug, Default)]
+struct Replica {
+    /// 起動時には繋がらなくてもよいよう、ヘルスチェックで初めて接続できた時に入る
+    conn: OnceLock<DatabaseConnection>,
+    healthy: AtomicBool,
+}
+
+impl DbHandle {
+    /// primaryに接続する. replicaは設定されていればバックグラウンドで接続し、状態を監視し続ける.
+    pub async fn connect(env: &Env) -> Result<Self, DbErr> {
+        let primary = connect(env, env.db_url.expose()).await?;
+        let replica = env.db_replica_url.as_ref().map(|url| {
+            let replica = Arc::new(Replica::default());
+            tokio::spawn(watch_replica(
+                replica.clone(),
+                connect_options(env, url.expose()),
+                Duration::from_secs(env.db_replica_health_check_secs),
+            ));
+            replica
+        });
+        Ok(Self { primary, replica })
+    }
+
+    pub fn primary(&self) -> &DatabaseConnection {
+        &self.primary
+    }
+
+    /// 読み取り専用のクエリに使う. 直前の書き込みが反映されている保証は無い.
+    pub fn reader(&self) -> &DatabaseConnection {
+        self.replica
+            .as_deref()
+            .filter(|r| r.healthy.load(Ordering::Relaxed))
+            .and_then(|r| r.conn.get())
+            .unwrap_or(&self.primary)
+    }
+
+    /// replicaが設定されていればその状態. 未接続の間もエラーとする.
+    pub async fn ping_replica(&self) -> Option<Result<(), DbErr>> {
+        let replica = self.replica.as_deref()?;
+        Some(match replica.conn.get() {
+            Some(conn) => conn.ping().await,
+            None => Err(DbErr::Custom("replicaに未接続".to_string())),
+        })
+    }
+
+    /// 接続済みのreplica. メトリクス用.
+    pub fn replica(&self) -> Option<&DatabaseConnection> {
+        self.replica.as_deref().and_then(|r| r.conn.get())
+    }
+
+    pub async fn close(self) -> Result<(), DbErr> {
+        if let Some(conn) = self.replica().cloned() {
+            conn.close().await?;
+        }
+        self.primary.close().await
+    }
+}
+
+/// replicaへの接続と疎通確認を定期的に行い、使えるかどうかを切り替える.
+async fn watch_replica(replica: Arc<Replica>, opt: ConnectOptions, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let result = match replica.conn.get() {
+            Some(conn) => conn.ping().await,
+            None => Database::connect(opt.clone())
+                .await
+                .map(|conn| drop(replica.conn.set(conn))),
+        };
+
+        let was_healthy = replica.healthy.swap(result.is_ok(), Ordering::Relaxed);
+        match result {
+            Err(e) if was_healthy => {
+                tracing::warn!("replicaが使えないため、読み取りをprimaryに切り替える: {e}")
+            }
+            Err(e) => tracing::debug!("replicaはまだ使えない: {e}"),
+            Ok(()) if !was_healthy => tracing::info!("replicaからの読み取りを開始する"),
+            Ok(()) => {}
+        }
+    }
+}
+
+/// リクエスト内で使うDB. `read_your_writes`を呼ぶと、そのリクエストの以降の読み取りは全てprimaryから行う.
+#[derive(Clone, Debug)]
+pub struct Db {
+    handle: DbHandle,
+    read_your_writes: ReadYourWrites,
+}
+
+/// 同じリクエストで取り出した`Db`の間で共有する.
+#[derive(Clone, Debug, Default)]
+struct ReadYourWrites(Arc<AtomicBool>);
+
+impl Db {
+    pub fn read(&self) -> &DatabaseConnection {
+        if self.read_your_writes.0.load(Ordering::Relaxed) {
+            self.handle.primary()
+        } else {
+            self.handle.reader()
+        }
+    }
+
+    pub fn write(&self) -> &DatabaseConnection {
+        self.handle.primary()
+    }
+
+    /// 書き込んだ内容をすぐに読む場合に呼ぶ. replicaの遅延の影響を受けなくなる.
+    pub fn read_your_writes(&self) {
+        self.read_your_writes.0.store(true, Ordering::Relaxed);
+    }
+}
+
+#[async_trait]
+impl extract::FromRequestParts<AppState> for Db {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let read_your_writes = parts
+            .extensions
+            .get_or_insert_default::<ReadYourWrites>()
+            .clone();
+        Ok(Self {
+            handle: state.db_client.clone(),
+            read_your_writes,
+        })
+    }
+}
+
+/// ルート全体をread-your-writesにするミドルウェア.
+pub async fn read_your_writes(mut req: extract::Request, next: middleware::Next) -> Response {
+    req.extensions_mut()
+        .insert(ReadYourWrites(Arc::new(AtomicBool::new(true))));
+    next.run(req).await
+}
+
+/// リクエスト単位のトランザクション. 最初に`begin`した時にprimaryで開始し、
+/// `transaction`ミドルウェアがハンドラの結果に応じてcommitかrollbackする.
+pub struct Tx {
+    db: DatabaseConnection,
+    slot: TxSlot,
+    logger: Logger,
+    req_id: Ulid,
+}
+
+/// `transaction`ミドルウェアと、同じリクエストで取り出した`Tx`の間で共有する.
+#[derive(Clone, Default)]
+struct TxSlot(Arc<OnceCell<OpenTx>>);
+
+struct OpenTx {
+    txn: DatabaseTransaction,
+    started: Instant,
+}
+
+impl Tx {
+    /// 未開始なら開始する. 同じリクエストでは何度呼んでも同じトランザクションを返す.
+    /// `Tx`はハンドラの中だけで使う. 別タスクに渡してリクエストより長く持つとcommitできない.
+    pub async fn begin(&self) -> Result<&DatabaseTransaction, AppError> {
+        let open = self
+            .slot
+            .0
+            .get_or_try_init(|| async {
+                let txn = self.db.begin().await?;
+                Ok(OpenTx {
+                    txn,
+                    started: Instant::now(),
+                })
+            })
+            .await
+            .map_err(|e: DbErr| {
+                Panic::from_error(&e).into_app_error(self.logger.clone(), &self.req_id)
+            })?;
+        Ok(&open.txn)
+    }
+}
+
+#[async_trait]
+impl extract::FromRequestParts<AppState> for Tx {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let slot = parts
+            .extensions
+            .get::<TxSlot>()
+            .cloned()
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let logger = Logger::from_request_parts(parts, state).await?;
+        let ctx = ReqScopedState::from_request_parts(parts, state).await?;
+        Ok(Self {
+            db: state.db_client.primary().clone(),
+            slot,
+            logger,
+            req_id: ctx.req_id,
+        })
+    }
+}
+
+/// `Tx`を使えるようにするミドルウェア. ハンドラが`AppError`を返した場合はrollbackし、
+/// それ以外はcommitする. 開始していなければ何もしない.
+pub async fn transaction(mut req: extract::Request, next: middleware::Next) -> Response {
+    let slot = TxSlot::default();
+    req.extensions_mut().insert(slot.clone());
+    let logger = req.extensions().get::<Logger>().cloned();
+    let req_id = req
+        .extensions()
+        .get::<ReqScopedState>()
+        .map(|ctx| ctx.req_id);
+
+    let res = next.run(req).await;
+
+    let open = match Arc::try_unwrap(slot.0) {
+        Ok(slot) => slot.into_inner(),
+        // ハンドラの外に持ち出された`Tx`がトランザクションを使っている. 破棄された時にrollbackされる
+        Err(slot) if slot.initialized() => {
+            return failed_to_commit(
+                DbErr::Custom(
+                    "リクエストの終了後も使われているトランザクションはcommitしない".to_string(),
+                ),
+                logger,
+                req_id,
+            );
+        }
+        Err(_) => None,
+    };
+    let Some(OpenTx { txn, started }) = open else {
+        return res;
+    };
+    let failed = handler_failed(&res);
+    let (outcome, result) = if failed {
+        ("rollback", txn.rollback().await)
+    } else {
+        ("commit", txn.commit().await)
+    };
+
+    let duration_ms = started.elapsed().as_millis() as u64;
+    macro_rules! log {
+        ($level:ident, $($message:tt)+) => {
+            tracing::$level!(
+                transaction.outcome = outcome,
+                transaction.duration_ms = duration_ms,
+                req_id = req_id.map(tracing::field::display),
+                $($message)+
+            )
+        };
+    }
+    match result {
+        Ok(()) => {
+            log!(info, "トランザクションを終了した: {outcome}");
+            res
+        }
+        // rollbackに失敗してもハンドラの結果は反映されないので、エラーのレスポンスをそのまま返す
+        Err(e) if failed => {
+            log!(warn, "トランザクションを終了できなかった: {outcome}: {e}");
+            res
+        }
+        // commitに失敗した場合、ハンドラの結果は反映されていないのでエラーを返す
+        Err(e) => {
+            log!(warn, "トランザクションを終了できなかった: {outcome}");
+            failed_to_commit(e, logger, req_id)
+        }
+    }
+}
 
-pub async fn connect(url: &str) -> DatabaseConnection {
+/// ハンドラが`AppError`を返したか. `AppError`は`ProblemDetails`をextensionsに入れて返す.
+fn handler_failed(res: &Response) -> bool {
+    res.extensions().get::<ProblemDetails>().is_some()
+}
+
+/// commitできなかった場合のレスポンス. 元のレスポンスに関わらず500にする.
+fn failed_to_commit(e: DbErr, logger: Option<Logger>, req_id: Option<Ulid>) -> Response {
+    match (logger, req_id) {
+        (Some(logger), Some(req_id)) => Panic::from_error(&e)
+            .into_app_error(logger, &req_id)
+            .into_response(),
+        _ => {
+            tracing::error!("トランザクションをcommitできなかった: {e}");
+            ProblemDetails::new(
+                ErrorCode::UNEXPECTED,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                None,
+            )
+            .into_response()
+        }
+    }
+}
+
+/// 接続プールを作る.
+async fn connect(env: &Env, url: &str) -> Result<DatabaseConnection, DbErr> {
+    connect_with_retry(connect_options(env, url), env.db_connect_retries).await
+}
+
+/// DBの起動を待てるよう、失敗したら間隔を空けて`retries`回まで再試行する.
+async fn connect_with_retry(
+    opt: ConnectOptions,
+    retries: u32,
+) -> Result<DatabaseConnection, DbErr> {
+    let mut attempt = 0;
+    loop {
+        match Database::connect(opt.clone()).await {
+            Ok(db) => return Ok(db),
+            Err(e) if attempt < retries => {
+                let wait = jitter(retry_delay(attempt));
+                attempt += 1;
+                tracing::warn!(
+                    "DBに接続できなかった. {}ms後に再試行する ({attempt}/{retries}): {e}",
+                    wait.as_millis(),
+                );
+                tokio::time::sleep(wait).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+const CONNECT_RETRY_BASE: Duration = Duration::from_secs(1);
+const CONNECT_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// `attempt`回目の失敗の後に待つ時間. 倍々に増やし、`CONNECT_RETRY_MAX`で頭打ちにする.
+fn retry_delay(attempt: u32) -> Duration {
+    CONNECT_RETRY_BASE
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(CONNECT_RETRY_MAX)
+}
+
+/// 複数のインスタンスが同時に再試行しないよう、±25%の範囲でずらす.
+fn jitter(wait: Duration) -> Duration {
+    wait.mul_f64(rand::random::<f64>() * 0.5 + 0.75)
+}
+
+/// primaryとreplicaで同じプールの設定を使う.
+fn connect_options(env: &Env, url: &str) -> ConnectOptions {
+    let mut opt = ConnectOptions::new(connection_url(
+        url,
+        &env.db_application_name,
+        env.db_statement_timeout_ms,
+    ));
+    opt.max_connections(env.db_max_connections)
+        .min_connections(env.db_min_connections)
+        .connect_timeout(Duration::from_secs(env.db_connect_timeout_secs))
+        .acquire_timeout(Duration::from_secs(env.db_acquire_timeout_secs))
+        .idle_timeout(Duration::from_secs(env.db_idle_timeout_secs))
+        .max_lifetime(Duration::from_secs(env.db_max_lifetime_secs))
+        .sqlx_logging(env.db_log_level != log::LevelFilter::Off)
+        .sqlx_logging_level(env.db_log_level);
+    opt
+}
+
+/// `application_name`と`statement_timeout`は接続時のパラメータとしてURLで渡す.
+/// URLに同じパラメータがあれば設定の値で置き換え、`options`のそれ以外の指定は残す.
+/// URLは設定の読み込み時に検証済み.
+fn connection_url(url: &str, application_name: &str, statement_timeout_ms: u64) -> String {
+    let mut url = Url::parse(url).expect("DBのURLは検証済みのはず");
+    let mut options = Vec::new();
+    let mut pairs = Vec::new();
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "application_name" => {}
+            "options" => options.extend(without_statement_timeout(&value)),
+            _ => pairs.push((key.into_owned(), value.into_owned())),
+        }
+    }
+    options.push(format!("-c statement_timeout={statement_timeout_ms}"));
+
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("application_name", application_name)
+        .append_pair("options", &options.join(" "));
+    url.into()
+}
+
+/// `options`の値から`statement_timeout`の指定を除き、残りを引数ごとに返す.
+fn without_statement_timeout(options: &str) -> Vec<String> {
+    let is_timeout = |arg: &str| arg.starts_with("statement_timeout=");
+    let mut args = Vec::new();
+    let mut tokens = options.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "-c" => match tokens.next() {
+                Some(arg) if is_timeout(arg) => {}
+                Some(arg) => args.push(format!("-c {arg}")),
+                None => args.push(token.to_string()),
+            },
+            _ if token.strip_prefix("-c").is_some_and(is_timeout)
+                || token.strip_prefix("--").is_some_and(is_timeout) => {}
+            _ => args.push(token.to_string()),
+        }
+    }
+    args
+}
+
+/// マイグレーションの排他に使うadvisory lockのキー. 値に意味は無く、他の用途と重ならなければよい.
+const MIGRATION_LOCK_KEY: i64 = 0x7765_6261_7069;
+
+/// 未適用のマイグレーションを全て適用する. 複数のインスタンスが同時に起動しても順に実行されるよう、
+/// advisory lockを取ってから行う. 接続は`retries`回まで再試行する.
+pub async fn migrate_with_lock(url: &str, retries: u32) -> Result<(), DbErr> {
+    run_with_lock(url, retries, Direction::Up).await
+}
+
+enum Direction {
+    Up,
+    /// 直近から戻す数
+    Down(u32),
+}
+
+/// `migrate_with_lock`と同じロックの下でマイグレーションを適用するか戻す.
+async fn run_with_lock(url: &str, retries: u32, direction: Direction) -> Result<(), DbErr> {
+    // advisory lockはセッション単位なので、ロックと実行を同じ接続で行う
     let mut opt = ConnectOptions::new(url);
-    opt.max_connections(100)
-        .min_connections(5)
-        .connect_timeout(Duration::from_secs(8))
-        .acquire_timeout(Duration::from_secs(8))
-        .idle_timeout(Duration::from_secs(8))
-        .max_lifetime(Duration::from_secs(8))
-        .sqlx_logging(true);
-    // .sqlx_logging_level(log::LevelFilter::Info)
-    // .set_schema_search_path("my_schema"); // Setting default PostgreSQL schema
-
-    Database::connect(opt).await.expect("db接続に成功すべき")
+    opt.max_connections(1)
+        .min_connections(1)
+        .sqlx_logging(false);
+    let db = connect_with_retry(opt, retries).await?;
+
+    tracing::info!("マイグレーションのロックを待つ");
+    db.execute(lock_statement(&db, "pg_advisory_lock")).await?;
+    let result = match direction {
+        Direction::Up => Migrator::up(&db, None).await,
+        Direction::Down(steps) => Migrator::down(&db, Some(steps)).await,
+    };
+    // ロックは接続を閉じれば解放されるので、失敗してもマイグレーションの結果を優先する
+    if let Err(e) = db.execute(lock_statement(&db, "pg_advisory_unlock")).await {
+        tracing::warn!("マイグレーションのロックを解放できなかった: {e}");
+    }
+    if let Err(e) = db.close().await {
+        tracing::warn!("マイグレーションに使った接続を閉じられなかった: {e}");
+    }
+
+    result.inspect(|_| match direction {
+        Direction::Up => tracing::info!("マイグレーションを適用した"),
+        Direction::Down(steps) => tracing::info!("マイグレーションを{steps}個戻した"),
+    })
+}
+
+fn lock_statement(db: &DatabaseConnection, function: &str) -> Statement {
+    Statement::from_sql_and_values(
+        db.get_database_backend(),
+        format!("SELECT {function}($1)"),
+        [MIGRATION_LOCK_KEY.into()],
+    )
+}
+
+/// `webapi migrate <command>`. 手動でマイグレーションを操作する.
+///
+/// - `up`: 未適用のものを全て適用する
+/// - `down [n]`: 直近からn個(既定は1)を戻す
+/// - `status`: 適用状況を表示する
+pub async fn run_migrate_command(url: &str, retries: u32, args: &[String]) -> Result<(), String> {
+    let direction = match args.first().map(String::as_str).unwrap_or("up") {
+        "up" => Direction::Up,
+        "down" => Direction::Down(match args.get(1) {
+            Some(n) => n.parse().map_err(|_| format!("downの引数は戻す数: {n}"))?,
+            None => 1,
+        }),
+        "status" => return print_migration_status(url, retries).await,
+        command => {
+            return Err(format!(
+                "不明なコマンド: {command} (up, down [n], statusのいずれか)"
+            ))
+        }
+    };
+    run_with_lock(url, retries, direction)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn print_migration_status(url: &str, retries: u32) -> Result<(), String> {
+    let db = connect_with_retry(ConnectOptions::new(url), retries)
+        .await
+        .map_err(|e| e.to_string())?;
+    let result = Migrator::get_migration_with_status(&db).await;
+    if let Err(e) = db.close().await {
+        tracing::warn!("マイグレーションに使った接続を閉じられなかった: {e}");
+    }
+
+    for migration in result.map_err(|e| e.to_string())? {
+        println!("{}\t{}", migration.status(), migration.name());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::i18n::MessageKey;
+
+    #[test]
+    fn rolls_back_only_error_responses() {
+        assert!(!handler_failed(&StatusCode::OK.into_response()));
+        // ステータスではなくAppErrorかどうかで決める
+        assert!(!handler_failed(&StatusCode::NOT_FOUND.into_response()));
+        assert!(handler_failed(
+            &AppError::WorkflowException(
+                StatusCode::CONFLICT,
+                MessageKey::new("error.version.conflict")
+            )
+            .into_response()
+        ));
+    }
+
+    #[test]
+    fn failed_commit_is_always_500() {
+        let res = failed_to_commit(DbErr::Custom("commit".to_string()), None, None);
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(handler_failed(&res));
+    }
+
+    #[test]
+    fn merges_connection_parameters_into_the_url() {
+        let url = connection_url("postgres://u:p@localhost/app", "webapi", 5000);
+        assert_eq!(
+            url,
+            "postgres://u:p@localhost/app?application_name=webapi&options=-c+statement_timeout%3D5000"
+        );
+
+        let url = connection_url(
+            "postgres://u:p@localhost/app?sslmode=require&application_name=old\
+             &options=-c%20search_path%3Dapp%20-c%20statement_timeout%3D1%20--statement_timeout%3D2",
+            "webapi",
+            5000,
+        );
+        let url = Url::parse(&url).unwrap();
+        let pairs: Vec<_> = url.query_pairs().into_owned().collect();
+        assert_eq!(
+            pairs,
+            [
+                ("sslmode".to_string(), "require".to_string()),
+                ("application_name".to_string(), "webapi".to_string()),
+                (
+                    "options".to_string(),
+                    "-c search_path=app -c statement_timeout=5000".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_max() {
+        let delays: Vec<u64> = (0..7).map(|n| retry_delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        assert_eq!(retry_delay(u32::MAX), CONNECT_RETRY_MAX);
+    }
+
+    #[test]
+    fn jitter_stays_within_a_quarter() {
+        for _ in 0..100 {
+            let wait = jitter(Duration::from_secs(4));
+            assert!(wait >= Duration::from_secs(3) && wait <= Duration::from_secs(5));
+        }
+    }
 }
@@ -1,43 +1,97 @@
+pub mod catch_panic;
+pub mod config;
+pub mod discovery;
 pub mod env;
+pub mod error_report;
+pub mod http_client;
+pub mod i18n;
 pub mod logger;
+pub mod metrics;
+pub mod problem;
+pub mod secret;
+pub mod server;
 pub mod session;
 pub mod system;
-use self::env::Env;
+pub mod trace_context;
+pub mod versioning;
+use self::{
+    config::Settings, discovery::Discovery, env::Env, error_report::ErrorReporting,
+    http_client::HttpClient, i18n::Locale, metrics::Metrics, trace_context::TraceContext,
+};
+use crate::db::DbHandle;
+use arc_swap::ArcSwap;
 use axum::{
     async_trait, extract,
-    http::{request::Parts, StatusCode},
+    http::{request::Parts, HeaderMap, StatusCode},
 };
 use chrono::{DateTime, Utc};
-use sea_orm::DatabaseConnection;
-use serde_json::Value;
 use std::fmt::Debug;
 use std::ops::Deref;
+use std::sync::Arc;
 use ulid::Ulid;
 
 /// アプリケーション全体での共有する状態. DBコネクションなどを持たせる.
 #[derive(Clone)]
 pub struct AppState {
-    pub db_client: DatabaseConnection,
+    /// ハンドラでは`db::Db`を通して使う
+    pub db_client: DbHandle,
     pub env: Immutable<Env>,
-    pub discovery_json: Value,
-    // pub jwk_set: JwkSet,
+    pub settings: Reloadable<Settings>,
+    /// OpenID Providerの情報. 取得できるまでは空.
+    pub discovery: Discovery,
+    pub metrics: Metrics,
+    /// 外部へのHTTPリクエストに使う
+    pub http_client: HttpClient,
+    /// 想定外のエラーの報告. 報告先が設定されていなければ`None`.
+    pub error_reporting: Option<ErrorReporting>,
 }
 
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
 /// リクエストごとに分離された状態.
 #[derive(Clone, Debug)]
 pub struct ReqScopedState {
     pub ts: DateTime<Utc>,
     pub req_id: Ulid,
+    /// 呼び出し元が`X-Request-Id`を付けていればその値、無ければ`req_id`.
+    pub request_id: String,
+    pub trace: TraceContext,
+    /// 表示言語
+    pub locale: Locale,
 }
 
 impl ReqScopedState {
-    pub fn new(req_id: Ulid) -> Self {
+    pub fn new(req_id: Ulid, headers: &HeaderMap) -> Self {
         let ts = DateTime::from_timestamp_millis(req_id.timestamp_ms() as i64).unwrap();
+        let request_id = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| is_valid_request_id(v))
+            .map(str::to_string)
+            .unwrap_or_else(|| req_id.to_string());
+        let trace = TraceContext::from_headers(headers);
+        let locale = Locale::from_headers(headers);
 
-        Self { req_id, ts }
+        Self {
+            req_id,
+            ts,
+            request_id,
+            trace,
+            locale,
+        }
+    }
+
+    /// 外部へのリクエストに`X-Request-Id`とトレースコンテキストを付与する.
+    pub fn propagate(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        self.trace
+            .inject(builder.header(REQUEST_ID_HEADER, &self.request_id))
     }
 }
 
+fn is_valid_request_id(v: &str) -> bool {
+    !v.is_empty() && v.len() <= 128 && v.bytes().all(|b| b.is_ascii_graphic())
+}
+
 #[async_trait]
 impl<S: Send + Sync> extract::FromRequestParts<S> for ReqScopedState {
     type Rejection = StatusCode;
@@ -51,6 +105,7 @@ impl<S: Send + Sync> extract::FromRequestParts<S> for ReqScopedState {
     }
 }
 
+/// バージョン付きのデータ. `versioning`で`ETag`として返す.
 pub struct Meta<'a, Data, CustomProps = NoneOfProps> {
     pub data: &'a Data,
     pub version_tag: Ulid,
@@ -69,3 +124,28 @@ impl<T> Deref for Immutable<T> {
         &self.0
     }
 }
+
+/// 実行中に差し替わる値. 読む側は`load`でその時点の値を得る.
+#[derive(Debug)]
+pub struct Reloadable<T>(Arc<ArcSwap<T>>);
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(ArcSwap::from_pointee(value)))
+    }
+
+    pub fn load(&self) -> Arc<T> {
+        self.0.load_full()
+    }
+
+    /// 差し替えは設定の再読み込みからのみ行う.
+    fn store(&self, value: T) {
+        self.0.store(Arc::new(value));
+    }
+}
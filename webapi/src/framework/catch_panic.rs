@@ -0,0 +1,141 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
+};
+
+use axum::{
+    extract,
+    http::StatusCode,
+    middleware,
+    response::{IntoResponse, Response},
+};
+use futures_util::FutureExt;
+
+use super::{
+    logger::Logger,
+    system::{self, IntoAppError, Panic},
+    ReqScopedState,
+};
+
+tokio::task_local! {
+    /// `catch_panic`がハンドラを実行している間だけ設定される. panic hookで捕まえたpanicの情報を入れる.
+    static CAUGHT: RefCell<Option<Panic>>;
+}
+
+/// panic hookを差し替える. ハンドラ内のpanicは`catch_panic`がログに出すので標準エラーには書かない.
+/// それ以外のpanicは元のhookに任せる.
+pub fn install_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = payload_message(info.payload());
+        let message = match info.location() {
+            Some(location) => format!("panicked at {location}: {message}"),
+            None => format!("panicked: {message}"),
+        };
+        let caught = CAUGHT.try_with(|slot| {
+            // バックトレースはunwindした後では取れないので、ここで取得しておく
+            let panic = Panic::with_backtrace(message, system::capture_backtrace());
+            *slot.borrow_mut() = Some(panic);
+        });
+        if caught.is_err() {
+            default_hook(info);
+        }
+    }));
+}
+
+/// ハンドラのpanicを`AppError::Unexpected`に変換するミドルウェア.
+pub async fn catch_panic(req: extract::Request, next: middleware::Next) -> Response {
+    let logger = req.extensions().get::<Logger>().cloned();
+    let ctx = req.extensions().get::<ReqScopedState>().cloned();
+
+    // 捕まえたpanicの情報はリクエストごとに用意するので、別のリクエストのものと取り違えない
+    let (result, caught) = CAUGHT
+        .scope(RefCell::new(None), async {
+            let result = AssertUnwindSafe(next.run(req)).catch_unwind().await;
+            (result, CAUGHT.with(|slot| slot.take()))
+        })
+        .await;
+
+    let payload = match result {
+        Ok(res) => return res,
+        Err(payload) => payload,
+    };
+    // `resume_unwind`ではhookが呼ばれないので、payloadだけから作る
+    let panic = caught.unwrap_or_else(|| {
+        Panic::with_backtrace(
+            format!("panicked: {}", payload_message(payload.as_ref())),
+            None,
+        )
+    });
+    match (logger, ctx) {
+        (Some(logger), Some(ctx)) => panic.into_app_error(logger, &ctx.req_id).into_response(),
+        // setupより外側で使われた場合. ログに紐付けられるものが無い.
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::error_report::UnexpectedError;
+    use axum::{body::Body, routing, Router};
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+    use ulid::Ulid;
+
+    async fn panics(req: extract::Request) -> &'static str {
+        panic!("{}", req.uri().path())
+    }
+
+    async fn with_logger(mut req: extract::Request, next: middleware::Next) -> Response {
+        let ctx = ReqScopedState::new(Ulid::new(), req.headers());
+        let logger = Logger::new(&ctx, &req, &SocketAddr::from(([127, 0, 0, 1], 0)));
+        req.extensions_mut().insert(logger);
+        req.extensions_mut().insert(ctx);
+        next.run(req).await
+    }
+
+    async fn call(app: &Router, uri: &str) -> Response {
+        let req = extract::Request::builder()
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn converts_each_panic_separately() {
+        install_hook();
+        let app = Router::new()
+            .route("/a", routing::get(panics))
+            .route("/b", routing::get(panics))
+            .route("/ok", routing::get(|| async { "ok" }))
+            .layer(middleware::from_fn(catch_panic))
+            .layer(middleware::from_fn(with_logger));
+
+        for uri in ["/a", "/b"] {
+            let res = call(&app, uri).await;
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let error = res.extensions().get::<UnexpectedError>().unwrap();
+            // hookで取ったpanicの位置が入っている
+            assert!(
+                error.message.starts_with("panicked at "),
+                "{}",
+                error.message
+            );
+            assert!(error.message.ends_with(uri), "{}", error.message);
+        }
+        assert_eq!(call(&app, "/ok").await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use arc_swap::ArcSwapOption;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey};
+use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::Value;
+
+use super::{
+    env::{ClientAuthMethod, Env},
+    http_client::HttpClient,
+};
+
+/// 取得に失敗した場合の再試行間隔. 失敗するたびに倍にし、上限で止める.
+const RETRY_INITIAL: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// 未知の`kid`による取得し直しの最短間隔. 不正なトークンで取得が繰り返されないようにする.
+const ON_DEMAND_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 読み込んだdiscovery documentとJWKS.
+#[derive(Debug)]
+pub struct Provider {
+    pub metadata: ProviderMetadata,
+    pub jwk_set: JwkSet,
+    pub fetched_at: DateTime<Utc>,
+}
+
+/// discovery documentのうち、このアプリケーションが使う項目. 読み込み時に検証済み.
+#[derive(Clone, Debug)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: Url,
+    pub token_endpoint: Url,
+    pub jwks_uri: Url,
+    pub userinfo_endpoint: Option<Url>,
+    pub scopes_supported: Vec<String>,
+    /// IDトークンの署名方式のうち、公開鍵で検証できるもの. RS256は必ず含む.
+    pub id_token_signing_alg_values_supported: Vec<Algorithm>,
+    /// 省略された場合は仕様どおり`client_secret_basic`のみとみなす.
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+/// 検証前のdiscovery document. 必須の項目が無い場合も検証でまとめて報告するため全て`Option`.
+#[derive(Deserialize)]
+struct RawMetadata {
+    issuer: Option<String>,
+    authorization_endpoint: Option<String>,
+    token_endpoint: Option<String>,
+    jwks_uri: Option<String>,
+    userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    response_types_supported: Vec<String>,
+    #[serde(default)]
+    id_token_signing_alg_values_supported: Vec<String>,
+    #[serde(default)]
+    scopes_supported: Vec<String>,
+    token_endpoint_auth_methods_supported: Option<Vec<String>>,
+}
+
+impl ProviderMetadata {
+    /// 必須のエンドポイントがあり、認可コードフローとRS256に対応していることを確認する.
+    /// 問題は全てまとめてエラーにする.
+    pub fn from_document(document: Value) -> Result<Self, String> {
+        let raw: RawMetadata = serde_json::from_value(document)
+            .map_err(|e| format!("discovery documentを解析できない: {e}"))?;
+        let mut errors = Vec::new();
+
+        let mut endpoint = |name: &str, value: Option<String>, required: bool| match value
+            .map(|v| Url::parse(&v).map_err(|e| format!("{name}が不正なURL: {v}: {e}")))
+        {
+            Some(Ok(url)) if url.scheme() == "https" || url.host_str() == Some("localhost") => {
+                Some(url)
+            }
+            Some(Ok(url)) => {
+                errors.push(format!("{name}はhttpsである必要がある: {url}"));
+                None
+            }
+            Some(Err(e)) => {
+                errors.push(e);
+                None
+            }
+            None if required => {
+                errors.push(format!("{name}が無い"));
+                None
+            }
+            None => None,
+        };
+        let authorization_endpoint =
+            endpoint("authorization_endpoint", raw.authorization_endpoint, true);
+        let token_endpoint = endpoint("token_endpoint", raw.token_endpoint, true);
+        let jwks_uri = endpoint("jwks_uri", raw.jwks_uri, true);
+        let userinfo_endpoint = endpoint("userinfo_endpoint", raw.userinfo_endpoint, false);
+
+        if raw.issuer.is_none() {
+            errors.push("issuerが無い".to_string());
+        }
+        if !raw.response_types_supported.iter().any(|t| t == "code") {
+            errors.push("response_types_supportedにcodeが無い".to_string());
+        }
+        // HS256などはクライアントシークレットで署名するので受け付けない. 未知の方式は無視する.
+        let algorithms: Vec<Algorithm> = raw
+            .id_token_signing_alg_values_supported
+            .iter()
+            .filter_map(|alg| alg.parse().ok())
+            .filter(|alg| !matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512))
+            .collect();
+        if !algorithms.contains(&Algorithm::RS256) {
+            errors.push("id_token_signing_alg_values_supportedにRS256が無い".to_string());
+        }
+
+        match (raw.issuer, authorization_endpoint, token_endpoint, jwks_uri) {
+            (Some(issuer), Some(authorization_endpoint), Some(token_endpoint), Some(jwks_uri))
+                if errors.is_empty() =>
+            {
+                Ok(Self {
+                    issuer,
+                    authorization_endpoint,
+                    token_endpoint,
+                    jwks_uri,
+                    userinfo_endpoint,
+                    scopes_supported: raw.scopes_supported,
+                    id_token_signing_alg_values_supported: algorithms,
+                    token_endpoint_auth_methods_supported: raw
+                        .token_endpoint_auth_methods_supported
+                        .unwrap_or_else(|| vec!["client_secret_basic".to_string()]),
+                })
+            }
+            _ => Err(format!("discovery documentが不正: {}", errors.join(", "))),
+        }
+    }
+}
+
+/// discovery documentの取得元.
+#[derive(Clone, Debug)]
+enum Source {
+    Remote(String),
+    /// ローカル開発用. JWKSのファイルが無ければ`jwks_uri`から取得する.
+    File {
+        discovery: PathBuf,
+        jwks: Option<PathBuf>,
+    },
+}
+
+/// OpenID Providerの情報. 起動時には取得を待たず、バックグラウンドで取得と定期的な更新を行う.
+#[derive(Clone)]
+pub struct Discovery(Arc<Inner>);
+
+struct Inner {
+    provider: ArcSwapOption<Provider>,
+    last_error: Mutex<Option<String>>,
+    client: HttpClient,
+    source: Source,
+    auth_method: ClientAuthMethod,
+    /// `refresh_for_unknown_kid`で最後に取得し直した時刻
+    last_on_demand: tokio::sync::Mutex<Option<Instant>>,
+}
+
+impl Discovery {
+    /// 取得タスクを起動する. 取得できるまでは`get`が`None`を返す.
+    pub fn spawn(env: &Env, client: HttpClient) -> Self {
+        let source = match &env.oidc_discovery_file {
+            Some(path) => Source::File {
+                discovery: PathBuf::from(path),
+                jwks: env.oidc_jwks_file.as_ref().map(PathBuf::from),
+            },
+            None => Source::Remote(env.oidc_discovery_url.clone()),
+        };
+        let this = Self::new(client, source, env.oidc_client_auth_method);
+        tokio::spawn(this.clone().run(Duration::from_secs(env.oidc_refresh_secs)));
+
+        this
+    }
+
+    fn new(client: HttpClient, source: Source, auth_method: ClientAuthMethod) -> Self {
+        Self(Arc::new(Inner {
+            provider: ArcSwapOption::empty(),
+            last_error: Mutex::new(Some("discovery documentを取得中".to_string())),
+            client,
+            source,
+            auth_method,
+            last_on_demand: tokio::sync::Mutex::new(None),
+        }))
+    }
+
+    pub fn get(&self) -> Option<Arc<Provider>> {
+        self.0.provider.load_full()
+    }
+
+    /// IDトークンの`kid`が手元のJWKSに無い場合に呼ぶ. 鍵の更新に追従するため、定期更新を待たずに取得し直す.
+    ///
+    /// 取得し直すのは`ON_DEMAND_INTERVAL`に1回まで. それより短い間隔や取得に失敗した場合は今の情報を返す.
+    pub async fn refresh_for_unknown_kid(&self) -> Option<Arc<Provider>> {
+        // 同時に呼ばれた場合は先の取得が終わるのを待ち、その結果を使う
+        let mut last = self.0.last_on_demand.lock().await;
+        if last.is_some_and(|at| at.elapsed() < ON_DEMAND_INTERVAL) {
+            return self.get();
+        }
+        *last = Some(Instant::now());
+
+        match self.load().await {
+            Ok(provider) => {
+                tracing::info!("未知のkidがあったのでJWKSを取得し直した");
+                let provider = Arc::new(provider);
+                self.0.provider.store(Some(provider.clone()));
+                Some(provider)
+            }
+            Err(LoadError::Invalid(e) | LoadError::Unreachable(e)) => {
+                tracing::warn!("JWKSを取得し直せない: {e}");
+                self.get()
+            }
+        }
+    }
+
+    async fn load(&self) -> Result<Provider, LoadError> {
+        load(&self.0.client, &self.0.source, self.0.auth_method).await
+    }
+
+    /// readiness用. 一度も取得できていなければ直近のエラー.
+    pub fn status(&self) -> Result<(), String> {
+        if self.get().is_some() {
+            return Ok(());
+        }
+        Err(self
+            .0
+            .last_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+            .unwrap_or_else(|| "discovery documentが読み込まれていない".to_string()))
+    }
+
+    /// 取得に失敗した場合はバックオフしながら再試行する. 更新に失敗しても取得済みの情報は使い続ける.
+    ///
+    /// 内容が不正な場合は再試行しても直らないので、起動時であればプロセスを終了する.
+    async fn run(self, refresh_interval: Duration) {
+        let mut retry = RETRY_INITIAL;
+
+        loop {
+            match self.load().await {
+                Ok(provider) => {
+                    tracing::info!(
+                        "discovery documentを読み込んだ: {}",
+                        provider.metadata.issuer
+                    );
+                    self.0.provider.store(Some(Arc::new(provider)));
+                    *self.0.last_error.lock().unwrap_or_else(|e| e.into_inner()) = None;
+                    retry = RETRY_INITIAL;
+                    tokio::time::sleep(refresh_interval).await;
+                }
+                Err(LoadError::Invalid(e)) if self.get().is_none() => {
+                    tracing::error!("discovery documentが不正なため終了する: {e}");
+                    std::process::exit(1);
+                }
+                Err(LoadError::Invalid(e)) => {
+                    tracing::error!(
+                        "更新したdiscovery documentが不正. 取得済みの情報を使い続ける: {e}"
+                    );
+                    tokio::time::sleep(refresh_interval).await;
+                }
+                Err(LoadError::Unreachable(e)) => {
+                    // 同時に再起動したインスタンスが揃って再試行しないよう揺らす
+                    let wait = retry.mul_f64(rand::random::<f64>() * 0.5 + 0.75);
+                    tracing::warn!(
+                        "discovery documentを取得できない. {}ms後に再試行する: {e}",
+                        wait.as_millis()
+                    );
+                    *self.0.last_error.lock().unwrap_or_else(|e| e.into_inner()) = Some(e);
+                    tokio::time::sleep(wait).await;
+                    retry = (retry * 2).min(RETRY_MAX);
+                }
+            }
+        }
+    }
+}
+
+/// 取得の失敗. 内容が不正な場合は再試行しない.
+#[derive(Debug)]
+enum LoadError {
+    /// 接続できない、エラーのステータスが返った、ファイルを読めないなど
+    Unreachable(String),
+    /// JSONとして読めない、必須の項目が無い、対応していない方式しか無いなど
+    Invalid(String),
+}
+
+async fn load(
+    client: &HttpClient,
+    source: &Source,
+    auth_method: ClientAuthMethod,
+) -> Result<Provider, LoadError> {
+    let document: Value = match source {
+        Source::Remote(url) => fetch(client, url).await?,
+        Source::File { discovery, .. } => read_file(discovery)?,
+    };
+    let metadata = ProviderMetadata::from_document(document).map_err(LoadError::Invalid)?;
+    if !metadata
+        .token_endpoint_auth_methods_supported
+        .iter()
+        .any(|m| m == auth_method.as_str())
+    {
+        return Err(LoadError::Invalid(format!(
+            "OpenID Providerがクライアント認証方式{}に対応していない",
+            auth_method.as_str()
+        )));
+    }
+
+    let jwk_set: JwkSet = match source {
+        Source::File {
+            jwks: Some(path), ..
+        } => read_file(path)?,
+        _ => fetch(client, metadata.jwks_uri.as_str()).await?,
+    };
+    let jwk_set = usable_keys(jwk_set)?;
+
+    Ok(Provider {
+        metadata,
+        jwk_set,
+        fetched_at: Utc::now(),
+    })
+}
+
+/// 検証に使えない鍵(未対応の種類や壊れた値)を除く. 1つも残らなければ不正.
+fn usable_keys(jwk_set: JwkSet) -> Result<JwkSet, LoadError> {
+    let (keys, unusable): (Vec<_>, Vec<_>) = jwk_set
+        .keys
+        .into_iter()
+        .partition(|jwk| DecodingKey::from_jwk(jwk).is_ok());
+    for jwk in &unusable {
+        tracing::warn!(
+            "JWKSの鍵を検証に使えないので除く: kid={}",
+            jwk.common.key_id.as_deref().unwrap_or("-")
+        );
+    }
+    if keys.is_empty() {
+        return Err(LoadError::Invalid("JWKSに検証に使える鍵が無い".to_string()));
+    }
+    Ok(JwkSet { keys })
+}
+
+async fn fetch<T: DeserializeOwned>(client: &HttpClient, url: &str) -> Result<T, LoadError> {
+    client
+        .send(client.get(url), None)
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| LoadError::Unreachable(format!("{url}: {e}")))?
+        .json()
+        .await
+        .map_err(|e| {
+            if e.is_decode() {
+                LoadError::Invalid(format!("{url}: {e}"))
+            } else {
+                LoadError::Unreachable(format!("{url}: {e}"))
+            }
+        })
+}
+
+fn read_file<T: DeserializeOwned>(path: &Path) -> Result<T, LoadError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| LoadError::Unreachable(format!("{}: {e}", path.display())))?;
+    serde_json::from_str(&contents)
+        .map_err(|e| LoadError::Invalid(format!("{}: {e}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISCOVERY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/config/oidc/google.json");
+    const JWKS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/oidc_jwks.json");
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("webapi-discovery-{}-{name}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    async fn load_files(discovery: PathBuf, jwks: PathBuf) -> Result<Provider, LoadError> {
+        let source = Source::File {
+            discovery,
+            jwks: Some(jwks),
+        };
+        load(
+            &HttpClient::for_tests(),
+            &source,
+            ClientAuthMethod::ClientSecretBasic,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn loads_from_files() {
+        let jwks = std::fs::read_to_string(JWKS).unwrap();
+        let jwks = jwks.replacen(
+            r#"{"keys":["#,
+            r#"{"keys":[{"kty":"RSA","kid":"broken","n":"!","e":"AQAB"},"#,
+            1,
+        );
+        let provider = load_files(DISCOVERY.into(), temp_file("jwks.json", &jwks))
+            .await
+            .unwrap();
+        assert_eq!(provider.metadata.issuer, "https://accounts.google.com");
+        // 壊れた鍵は除かれる
+        assert_eq!(provider.jwk_set.keys.len(), 1);
+        assert!(provider.jwk_set.find("test-key").is_some());
+
+        let empty = temp_file("empty-jwks.json", r#"{"keys":[]}"#);
+        let e = load_files(DISCOVERY.into(), empty).await.err().unwrap();
+        assert!(matches!(e, LoadError::Invalid(_)), "{e:?}");
+    }
+
+    #[tokio::test]
+    async fn separates_invalid_documents_from_unreachable_ones() {
+        let jwks = temp_file("jwks-for-invalid.json", r#"{"keys":[]}"#);
+
+        let document = temp_file("invalid.json", r#"{"issuer":"https://example.com"}"#);
+        let e = load_files(document, jwks.clone()).await.err().unwrap();
+        assert!(
+            matches!(&e, LoadError::Invalid(m) if m.contains("jwks_uri")),
+            "{e:?}"
+        );
+
+        let e = load_files("/nonexistent/discovery.json".into(), jwks)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(e, LoadError::Unreachable(_)), "{e:?}");
+    }
+
+    #[tokio::test]
+    async fn refreshes_keys_at_a_limited_rate() {
+        let original = std::fs::read_to_string(JWKS).unwrap();
+        let jwks = temp_file("rotating-jwks.json", &original);
+        let discovery = Discovery::new(
+            HttpClient::for_tests(),
+            Source::File {
+                discovery: DISCOVERY.into(),
+                jwks: Some(jwks.clone()),
+            },
+            ClientAuthMethod::ClientSecretBasic,
+        );
+        assert!(discovery.refresh_for_unknown_kid().await.is_some());
+
+        // 鍵がローテーションされても、直後の取得し直しでは読まない
+        std::fs::write(&jwks, original.replace("test-key", "rotated-key")).unwrap();
+        let provider = discovery.refresh_for_unknown_kid().await.unwrap();
+        assert!(provider.jwk_set.find("test-key").is_some());
+        assert!(provider.jwk_set.find("rotated-key").is_none());
+
+        *discovery.0.last_on_demand.lock().await = Some(Instant::now() - ON_DEMAND_INTERVAL);
+        let provider = discovery.refresh_for_unknown_kid().await.unwrap();
+        assert!(provider.jwk_set.find("rotated-key").is_some());
+        assert!(discovery
+            .get()
+            .unwrap()
+            .jwk_set
+            .find("rotated-key")
+            .is_some());
+    }
+}
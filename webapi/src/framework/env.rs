@@ -1,28 +1,124 @@
-use super::Immutable;
+use super::{secret::Secret, system::BacktraceCapture};
 
-#[derive(Clone)]
+/// `webapi migrate`で使う設定. 読み込みは`config::load_migrate`.
+#[derive(Clone, Debug)]
+pub struct MigrateEnv {
+    /// 接続先のパスワードを含む
+    pub db_url: Secret<String>,
+    /// 接続を再試行する回数
+    pub db_connect_retries: u32,
+}
+
+/// 起動時に決まり、変更には再起動が必要な設定. 読み込みは`config::load`.
+#[derive(Clone, Debug)]
 pub struct Env {
+    /// 待ち受けるアドレス. `unix_socket`を指定した場合は使わない.
+    pub host: String,
+    pub port: u16,
+    /// Unixドメインソケットのパス
+    pub unix_socket: Option<String>,
+    /// PEM形式の証明書と秘密鍵. 両方指定するとHTTPSで待ち受ける.
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    /// 終了時に処理中のリクエストを待つ秒数
+    pub shutdown_timeout_secs: u64,
+    /// 外部へのHTTPリクエスト全体と接続のタイムアウト
+    pub http_client_timeout_secs: u64,
+    pub http_client_connect_timeout_secs: u64,
+    /// 冪等なリクエストを再試行する回数
+    pub http_client_max_retries: u32,
+    /// 外部へのリクエストに使うプロキシ. 認証情報を含みうる.
+    pub http_client_proxy: Option<Secret<String>>,
+    /// プロキシを経由しないホスト. カンマ区切り.
+    pub http_client_no_proxy: Option<String>,
+    /// 追加で信頼するCA証明書. PEM形式で複数含めてよい.
+    pub http_client_ca_bundle: Option<String>,
+    /// OpenID ProviderのdiscoveryのURL
+    pub oidc_discovery_url: String,
+    /// 指定するとURLからではなくファイルからdiscovery documentを読む. ローカル開発用.
+    pub oidc_discovery_file: Option<String>,
+    /// `oidc_discovery_file`と合わせて使う. 無ければ`jwks_uri`から取得する.
+    pub oidc_jwks_file: Option<String>,
+    /// discovery documentとJWKSを取得し直す間隔
+    pub oidc_refresh_secs: u64,
+    /// token endpointでのクライアント認証方式
+    pub oidc_client_auth_method: ClientAuthMethod,
+    /// `private_key_jwt`で署名するPEM形式のRSA秘密鍵と、そのkid
+    pub oidc_private_key: Option<Secret<String>>,
+    pub oidc_private_key_id: Option<String>,
     pub google_client_id: String,
     pub google_redirect_uri: String,
-    pub google_client_secret: String,
-    pub db_url: String,
+    pub google_client_secret: Secret<String>,
+    /// 接続先のパスワードを含む
+    pub db_url: Secret<String>,
+    /// 読み取り専用のクエリを送るreplica. 未設定なら全てprimaryに送る.
+    pub db_replica_url: Option<Secret<String>>,
+    /// replicaの疎通を確認する間隔
+    pub db_replica_health_check_secs: u64,
+    /// 起動時に未適用のマイグレーションを適用する
+    pub db_migrate_on_startup: bool,
+    /// 接続プールの大きさ
+    pub db_max_connections: u32,
+    pub db_min_connections: u32,
+    /// 接続の確立と、プールから接続を得るまでのタイムアウト
+    pub db_connect_timeout_secs: u64,
+    pub db_acquire_timeout_secs: u64,
+    /// 使われていない接続を閉じるまでの秒数と、接続を作り直すまでの秒数
+    pub db_idle_timeout_secs: u64,
+    pub db_max_lifetime_secs: u64,
+    /// 起動時の接続を再試行する回数
+    pub db_connect_retries: u32,
+    /// 1つのSQLの実行時間の上限. 0なら制限しない.
+    pub db_statement_timeout_ms: u64,
+    /// `pg_stat_activity`などに表示される接続元の名前
+    pub db_application_name: String,
+    /// 実行したSQLをログに出すレベル. `off`で出さない.
+    pub db_log_level: log::LevelFilter,
+    /// `/metrics`のBearerトークン.
+    pub metrics_token: Option<Secret<String>>,
+    /// `metrics_token`が未設定の場合に、ループバックからのトークン無しの取得を許可する. 既定は拒否する.
+    pub metrics_allow_loopback: bool,
+    /// `Panic`でバックトレースを取得するか. 既定は常に取得する.
+    pub backtrace_capture: BacktraceCapture,
+    /// 想定外のエラーの報告先. 未設定なら報告しない.
+    pub sentry_dsn: Option<Secret<String>>,
+    pub sentry_environment: Option<String>,
+    /// 報告する割合. 0.0から1.0.
+    pub error_report_sample_rate: f64,
+    /// 同じエラーを再度報告するまでの秒数.
+    pub error_report_dedup_secs: u64,
+}
+
+/// token endpointでのクライアント認証方式.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientAuthMethod {
+    ClientSecretBasic,
+    ClientSecretPost,
+    PrivateKeyJwt,
 }
 
-impl Env {
-    pub fn new() -> Immutable<Env> {
-        let google_client_id = std::env::var("GOOGLE_CLIENT_ID")
-            .expect("環境変数にGOOGLE_CLIENT_IDをセットしてください。");
-        let google_client_secret = std::env::var("GOOGLE_CLIENT_SECRET")
-            .expect("環境変数にGOOGLE_CLIENT_SECRETをセットしてください。");
-        let google_redirect_uri =
-            std::env::var("REDIRECT_URI").expect("環境変数にREDIRECT_URIをセットしてください。");
-        let db_url = std::env::var("DB_URL").expect("環境変数にDB_URLをセットしてください。");
-        Immutable(Env {
-            google_client_id,
-            google_redirect_uri,
-            google_client_secret,
-            db_url,
-        })
+impl ClientAuthMethod {
+    /// discovery documentの`token_endpoint_auth_methods_supported`での名前
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientAuthMethod::ClientSecretBasic => "client_secret_basic",
+            ClientAuthMethod::ClientSecretPost => "client_secret_post",
+            ClientAuthMethod::PrivateKeyJwt => "private_key_jwt",
+        }
     }
 }
 
+impl std::str::FromStr for ClientAuthMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client_secret_basic" => Ok(ClientAuthMethod::ClientSecretBasic),
+            "client_secret_post" => Ok(ClientAuthMethod::ClientSecretPost),
+            "private_key_jwt" => Ok(ClientAuthMethod::PrivateKeyJwt),
+            _ => Err(format!(
+                "client_secret_basic, client_secret_post, private_key_jwtのいずれか: {s}"
+            )),
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{self, State},
+    http::header,
+    middleware,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+
+use super::{
+    http_client::HttpClient,
+    logger::{Logger, LoggerInterface},
+    secret::Secret,
+    session::Session,
+    system::Frame,
+    ReqScopedState,
+};
+
+/// `AppError::Unexpected`がレスポンスのextensionsに入れる報告内容.
+#[derive(Clone, Debug)]
+pub struct UnexpectedError {
+    pub message: String,
+    pub backtrace: Vec<Frame>,
+    pub error_chain: Vec<String>,
+    pub req_id: String,
+}
+
+/// 報告するイベント. `UnexpectedError`にリクエストの情報を加えたもの.
+#[derive(Clone, Debug)]
+pub struct ErrorEvent {
+    pub error: UnexpectedError,
+    pub user_id: Option<String>,
+    pub request: RequestMetadata,
+    pub timestamp: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug)]
+pub struct RequestMetadata {
+    pub method: String,
+    /// クエリにはOIDCのcode/stateなどが含まれるので、パスのみ
+    pub path: String,
+    pub user_agent: Option<String>,
+    pub trace_id: Option<String>,
+    pub span_id: Option<String>,
+}
+
+/// 想定外のエラーの報告先.
+#[async_trait]
+pub trait ErrorReporter: Send + Sync {
+    async fn report(&self, event: &ErrorEvent) -> Result<(), String>;
+}
+
+/// 報告の窓口. サンプリングと重複排除をしてから`ErrorReporter`に渡す.
+#[derive(Clone)]
+pub struct ErrorReporting(Arc<Inner>);
+
+struct Inner {
+    reporter: Box<dyn ErrorReporter>,
+    /// 0.0から1.0. 1.0なら全て報告する.
+    sample_rate: f64,
+    /// 同じエラーをこの期間内に再度報告しない.
+    dedup_window: Duration,
+    recent: Mutex<HashMap<u64, Instant>>,
+}
+
+impl ErrorReporting {
+    pub fn new(
+        reporter: impl ErrorReporter + 'static,
+        sample_rate: f64,
+        dedup_window: Duration,
+    ) -> Self {
+        Self(Arc::new(Inner {
+            reporter: Box::new(reporter),
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            dedup_window,
+            recent: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// 報告は別タスクで行い、レスポンスを待たせない.
+    fn dispatch(&self, event: ErrorEvent, logger: Option<Logger>) {
+        if !self.should_send(&event) {
+            return;
+        }
+
+        let this = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = this.0.reporter.report(&event).await {
+                if let Some(logger) = logger {
+                    logger.warning(&format!("エラーの報告に失敗した: {e}"));
+                }
+            }
+        });
+    }
+
+    fn should_send(&self, event: &ErrorEvent) -> bool {
+        if rand::random::<f64>() >= self.0.sample_rate {
+            return false;
+        }
+
+        let now = Instant::now();
+        let fingerprint = fingerprint(&event.error);
+        let mut recent = self.0.recent.lock().unwrap_or_else(|e| e.into_inner());
+        recent.retain(|_, at| now.duration_since(*at) < self.0.dedup_window);
+        if recent.contains_key(&fingerprint) {
+            return false;
+        }
+        recent.insert(fingerprint, now);
+        true
+    }
+}
+
+/// メッセージと最も内側のフレームが同じなら同じエラーとみなす.
+fn fingerprint(error: &UnexpectedError) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    error.message.hash(&mut hasher);
+    if let Some(frame) = error.backtrace.first() {
+        frame.function.hash(&mut hasher);
+        frame.file.hash(&mut hasher);
+        frame.line.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// `AppError::Unexpected`が返った場合に報告するミドルウェア. setupより内側で使う.
+/// stateには`AppState::error_reporting`を渡す.
+pub async fn report(
+    State(reporting): State<Option<ErrorReporting>>,
+    req: extract::Request,
+    next: middleware::Next,
+) -> Response {
+    let Some(reporting) = reporting else {
+        return next.run(req).await;
+    };
+
+    let ctx = req.extensions().get::<ReqScopedState>().cloned();
+    let request = RequestMetadata {
+        method: req.method().to_string(),
+        path: req.uri().path().to_string(),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string),
+        trace_id: ctx.as_ref().map(|c| c.trace.trace_id.clone()),
+        span_id: ctx.as_ref().map(|c| c.trace.span_id.clone()),
+    };
+    let user_id = req.extensions().get::<Session>().map(|s| s.user.id.clone());
+    let logger = req.extensions().get::<Logger>().cloned();
+
+    let mut res = next.run(req).await;
+
+    if let Some(error) = res.extensions_mut().remove::<UnexpectedError>() {
+        let event = ErrorEvent {
+            error,
+            user_id,
+            request,
+            timestamp: Utc::now(),
+        };
+        reporting.dispatch(event, logger);
+    }
+
+    res
+}
+
+/// SentryのDSN. `{scheme}://{public_key}@{host}/{project_id}`.
+#[derive(Clone, Debug)]
+struct SentryDsn {
+    raw: Secret<String>,
+    public_key: String,
+    envelope_url: reqwest::Url,
+}
+
+impl SentryDsn {
+    fn parse(dsn: &str) -> Result<Self, String> {
+        let url = reqwest::Url::parse(dsn).map_err(|e| format!("DSNを解析できない: {e}"))?;
+        if url.username().is_empty() {
+            return Err("DSNに公開鍵が含まれていない".to_string());
+        }
+        let mut segments: Vec<&str> = url
+            .path_segments()
+            .map(|s| s.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+        let project_id = segments
+            .pop()
+            .ok_or("DSNにプロジェクトIDが含まれていない")?
+            .to_string();
+
+        let mut envelope_url = url.clone();
+        envelope_url
+            .set_username("")
+            .and_then(|_| envelope_url.set_password(None))
+            .map_err(|_| "DSNのURLが不正".to_string())?;
+        let prefix = segments.iter().map(|s| format!("{s}/")).collect::<String>();
+        envelope_url.set_path(&format!("/{prefix}api/{project_id}/envelope/"));
+
+        Ok(Self {
+            raw: Secret::new(dsn.to_string()),
+            public_key: url.username().to_string(),
+            envelope_url,
+        })
+    }
+}
+
+/// Sentryのenvelope形式で送る`ErrorReporter`. Sentry互換のサーバーであれば送り先は問わない.
+pub struct SentryReporter {
+    client: HttpClient,
+    dsn: SentryDsn,
+    environment: Option<String>,
+}
+
+impl SentryReporter {
+    /// 送信には`AppState`と同じ`HttpClient`を使い、プロキシやタイムアウトの設定とメトリクスを共有する.
+    pub fn new(client: HttpClient, dsn: &str, environment: Option<String>) -> Result<Self, String> {
+        Ok(Self {
+            client,
+            dsn: SentryDsn::parse(dsn)?,
+            environment,
+        })
+    }
+
+    fn envelope(&self, event: &ErrorEvent) -> String {
+        let event_id = format!("{:032x}", rand::random::<u128>());
+        let error = &event.error;
+
+        // Sentryのフレームは呼び出し元が先
+        let frames: Vec<Value> = error
+            .backtrace
+            .iter()
+            .rev()
+            .map(|frame| {
+                json!({
+                    "function": frame.function,
+                    "filename": frame.file,
+                    "lineno": frame.line,
+                    "colno": frame.column,
+                    "in_app": true,
+                })
+            })
+            .collect();
+
+        let mut payload = json!({
+            "event_id": event_id,
+            "timestamp": event.timestamp.to_rfc3339(),
+            "platform": "other",
+            "level": "error",
+            "logger": env!("CARGO_PKG_NAME"),
+            "release": concat!(env!("CARGO_PKG_NAME"), "@", env!("CARGO_PKG_VERSION")),
+            "exception": {
+                "values": [{
+                    "type": "Unexpected",
+                    "value": error.message,
+                    "stacktrace": { "frames": frames },
+                }],
+            },
+            "tags": { "req_id": error.req_id },
+            "request": {
+                "method": event.request.method,
+                "url": event.request.path,
+                "headers": event.request.user_agent.as_ref().map(|ua| json!({ "User-Agent": ua })),
+            },
+            "extra": { "error_chain": error.error_chain },
+        });
+        if let Some(environment) = &self.environment {
+            payload["environment"] = json!(environment);
+        }
+        if let Some(user_id) = &event.user_id {
+            payload["user"] = json!({ "id": user_id });
+        }
+        if let (Some(trace_id), Some(span_id)) = (&event.request.trace_id, &event.request.span_id) {
+            payload["contexts"] = json!({
+                "trace": { "trace_id": trace_id, "span_id": span_id },
+            });
+        }
+
+        let header = json!({
+            "event_id": event_id,
+            "dsn": self.dsn.raw.expose(),
+            "sent_at": Utc::now().to_rfc3339(),
+        });
+        let payload = payload.to_string();
+        let item_header = json!({ "type": "event", "length": payload.len() });
+
+        format!("{header}\n{item_header}\n{payload}\n")
+    }
+}
+
+#[async_trait]
+impl ErrorReporter for SentryReporter {
+    async fn report(&self, event: &ErrorEvent) -> Result<(), String> {
+        let auth = format!(
+            "Sentry sentry_version=7, sentry_key={}, sentry_client={}/{}",
+            self.dsn.public_key,
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+        );
+
+        let req = self
+            .client
+            .post(self.dsn.envelope_url.clone())
+            .header("X-Sentry-Auth", auth)
+            .header(header::CONTENT_TYPE, "application/x-sentry-envelope")
+            .body(self.envelope(event));
+        let res = self
+            .client
+            .send(req, None)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        if !res.status().is_success() {
+            return Err(format!(
+                "{}に{}が返った",
+                self.dsn.envelope_url,
+                res.status()
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::{
+        problem,
+        system::{AppError, Panic},
+    };
+    use axum::{body::Body, extract::ConnectInfo, routing, Router};
+    use std::net::SocketAddr;
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+    use ulid::Ulid;
+
+    /// 受け取ったenvelopeを返すSentryの代わりのサーバーを立てる.
+    async fn sentry_stub() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let app = Router::new().route(
+            "/api/1/envelope/",
+            routing::post(move |body: String| async move {
+                tx.send(body).unwrap();
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (addr, rx)
+    }
+
+    async fn unexpected(req: extract::Request) -> Result<(), AppError> {
+        let ctx = ReqScopedState::new(Ulid::new(), req.headers());
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let logger = Logger::new(&ctx, &req, &addr);
+        Err(AppError::Unexpected(
+            logger,
+            Panic::new("boom".to_string()),
+            ctx.req_id.to_string(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn reports_unexpected_error_through_render() {
+        let (addr, mut envelopes) = sentry_stub().await;
+        let reporter = SentryReporter::new(
+            HttpClient::for_tests(),
+            &format!("http://key@{addr}/1"),
+            None,
+        )
+        .unwrap();
+        let reporting = ErrorReporting::new(reporter, 1.0, Duration::from_secs(60));
+
+        let app = Router::new()
+            .route("/", routing::get(unexpected))
+            .layer(middleware::from_fn(problem::render))
+            .layer(middleware::from_fn_with_state(Some(reporting), report));
+        let req = extract::Request::builder()
+            .uri("/?code=secret-code&state=secret-state")
+            .extension(ConnectInfo(addr))
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 500);
+
+        let envelope = tokio::time::timeout(Duration::from_secs(5), envelopes.recv())
+            .await
+            .expect("envelopeが届かない")
+            .unwrap();
+        assert!(envelope.contains("\"value\":\"boom\""), "{envelope}");
+        assert!(!envelope.contains("secret-"), "{envelope}");
+        // ハンドラのフレームがバックトレースに含まれる
+        assert!(
+            envelope.contains("error_report::tests::unexpected"),
+            "{envelope}"
+        );
+    }
+
+    #[test]
+    fn parses_dsn() {
+        let dsn = SentryDsn::parse("https://abc@sentry.example.com/prefix/42").unwrap();
+        assert_eq!(dsn.public_key, "abc");
+        assert_eq!(
+            dsn.envelope_url.as_str(),
+            "https://sentry.example.com/prefix/api/42/envelope/"
+        );
+        assert!(SentryDsn::parse("https://sentry.example.com/42").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use reqwest::{Certificate, IntoUrl, Method, NoProxy, Proxy, RequestBuilder, Response, StatusCode};
+
+use super::{env::Env, metrics::Metrics, ReqScopedState};
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// 再試行までの待ち時間の基準. n回目の再試行は0から`RETRY_BASE * 2^(n-1)`の間で待つ.
+const RETRY_BASE: Duration = Duration::from_millis(200);
+
+/// 外部へのHTTPリクエストに使うクライアント. `AppState`に1つだけ持たせ、接続を使い回す.
+///
+/// タイムアウト、プロキシ、CA証明書は`Env`で設定する. 呼び出しごとにログとメトリクスを記録する.
+#[derive(Clone)]
+pub struct HttpClient(Arc<Inner>);
+
+struct Inner {
+    client: reqwest::Client,
+    metrics: Metrics,
+    max_retries: u32,
+}
+
+impl HttpClient {
+    pub fn new(env: &Env, metrics: Metrics) -> Result<Self, String> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(USER_AGENT)
+            .connect_timeout(Duration::from_secs(env.http_client_connect_timeout_secs))
+            .timeout(Duration::from_secs(env.http_client_timeout_secs));
+
+        if let Some(proxy) = &env.http_client_proxy {
+            let proxy = Proxy::all(proxy.expose().as_str())
+                .map_err(|e| format!("http_client_proxyが不正: {e}"))?
+                .no_proxy(
+                    env.http_client_no_proxy
+                        .as_deref()
+                        .and_then(NoProxy::from_string),
+                );
+            builder = builder.proxy(proxy);
+        }
+        if let Some(path) = &env.http_client_ca_bundle {
+            let pem = std::fs::read(path).map_err(|e| format!("{path}を読み込めない: {e}"))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .map_err(|e| format!("{path}をPEM形式の証明書として読めない: {e}"))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        Ok(Self(Arc::new(Inner {
+            client: builder.build().map_err(|e| e.to_string())?,
+            metrics,
+            max_retries: env.http_client_max_retries,
+        })))
+    }
+
+    /// 設定を読まずに作る. 再試行はしない.
+    #[cfg(test)]
+    pub(crate) fn for_tests() -> Self {
+        Self(Arc::new(Inner {
+            client: reqwest::Client::new(),
+            metrics: Metrics::new(),
+            max_retries: 0,
+        }))
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.0.client.get(url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.0.client.post(url)
+    }
+
+    /// リクエストを送る. `ctx`があれば`X-Request-Id`とトレースコンテキストを付け、ログに`req_id`を出す.
+    ///
+    /// 冪等なメソッドは、接続できない・タイムアウト・429/502/503/504の場合に再試行する.
+    pub async fn send(
+        &self,
+        builder: RequestBuilder,
+        ctx: Option<&ReqScopedState>,
+    ) -> reqwest::Result<Response> {
+        let builder = match ctx {
+            Some(ctx) => ctx.propagate(builder),
+            None => builder,
+        };
+        let mut request = builder.build()?;
+        let method = request.method().clone();
+        let url = request.url().clone();
+        let idempotent = matches!(
+            method,
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+        );
+
+        let mut attempt = 1;
+        loop {
+            // 本文がストリームの場合は複製できないので再試行しない
+            let retry = (idempotent && attempt <= self.0.max_retries)
+                .then(|| request.try_clone())
+                .flatten();
+
+            let start = Instant::now();
+            let result = self.0.client.execute(request).await;
+            self.record(&method, &url, &result, start.elapsed(), attempt, ctx);
+
+            match retry {
+                Some(next) if should_retry(&result) => {
+                    tokio::time::sleep(backoff(attempt)).await;
+                    request = next;
+                    attempt += 1;
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    fn record(
+        &self,
+        method: &Method,
+        url: &reqwest::Url,
+        result: &reqwest::Result<Response>,
+        elapsed: Duration,
+        attempt: u32,
+        ctx: Option<&ReqScopedState>,
+    ) {
+        let host = url.host_str().unwrap_or("-");
+        let status = match result {
+            Ok(res) => res.status().as_u16().to_string(),
+            Err(e) if e.is_timeout() => "timeout".to_string(),
+            Err(_) => "error".to_string(),
+        };
+        self.0
+            .metrics
+            .outbound_request(host, method.as_str(), &status, elapsed);
+
+        // クエリには秘密情報が含まれうるので出さない
+        macro_rules! log {
+            ($level:ident, $($message:tt)+) => {
+                tracing::$level!(
+                    outbound.method = method.as_str(),
+                    outbound.host = host,
+                    outbound.path = url.path(),
+                    outbound.status = status,
+                    outbound.duration_ms = elapsed.as_millis() as u64,
+                    outbound.attempt = attempt,
+                    req_id = ctx.map(|ctx| tracing::field::display(ctx.req_id)),
+                    $($message)+
+                )
+            };
+        }
+        match result {
+            Ok(res) if !res.status().is_server_error() => log!(debug, "外部API {method} {host}"),
+            Ok(_) => log!(warn, "外部API {method} {host}"),
+            Err(e) => log!(warn, "外部API {method} {host}: {e}"),
+        }
+    }
+}
+
+fn should_retry(result: &reqwest::Result<Response>) -> bool {
+    match result {
+        Ok(res) => matches!(
+            res.status(),
+            StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::SERVICE_UNAVAILABLE
+                | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(e) => e.is_connect() || e.is_timeout(),
+    }
+}
+
+/// 呼び出し元が揃って再試行しないよう、待ち時間は0から上限までの一様乱数にする.
+fn backoff(attempt: u32) -> Duration {
+    let max = RETRY_BASE * 2u32.saturating_pow(attempt - 1);
+    max.mul_f64(rand::random::<f64>())
+}
@@ -0,0 +1,176 @@
+use axum::http::{header, HeaderMap};
+use axum_extra::extract::CookieJar;
+
+use crate::settings::LOCALE_KEY;
+
+/// 表示言語. 対応していない言語が指定された場合は日本語.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Locale {
+    #[default]
+    Ja,
+    En,
+}
+
+impl Locale {
+    /// ユーザーの設定(`lang` cookie)、`Accept-Language`の順に見て決める.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let preference = CookieJar::from_headers(headers)
+            .get(LOCALE_KEY)
+            .and_then(|c| Locale::from_tag(c.value()));
+
+        preference
+            .or_else(|| {
+                headers
+                    .get(header::ACCEPT_LANGUAGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(Locale::from_accept_language)
+            })
+            .unwrap_or_default()
+    }
+
+    /// `ja-JP`のような言語タグから. 主言語だけを見る.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split(['-', '_']).next()?.trim().to_ascii_lowercase();
+        match primary.as_str() {
+            "ja" => Some(Locale::Ja),
+            "en" => Some(Locale::En),
+            _ => None,
+        }
+    }
+
+    /// qが最も高い対応言語を選ぶ.
+    pub fn from_accept_language(value: &str) -> Option<Self> {
+        value
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';').map(str::trim);
+                let locale = Locale::from_tag(params.next()?)?;
+                let q = params
+                    .find_map(|p| p.strip_prefix("q="))
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (q > 0.0).then_some((locale, q))
+            })
+            // 同じqなら先に書かれた方
+            .fold(
+                None,
+                |best: Option<(Locale, f32)>, (locale, q)| match best {
+                    Some((_, best_q)) if best_q >= q => best,
+                    _ => Some((locale, q)),
+                },
+            )
+            .map(|(locale, _)| locale)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Locale::Ja => "ja",
+            Locale::En => "en",
+        }
+    }
+}
+
+/// 翻訳メッセージのキー. 利用者に見せる文言は表示する時にリクエストの言語で引く.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageKey(&'static str);
+
+impl MessageKey {
+    pub const fn new(key: &'static str) -> Self {
+        Self(key)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+}
+
+/// 翻訳メッセージ. キーがカタログに無ければ`None`.
+pub fn message(locale: Locale, key: &str) -> Option<&'static str> {
+    let (ja, en) = catalog(key)?;
+    Some(match locale {
+        Locale::Ja => ja,
+        Locale::En => en,
+    })
+}
+
+/// テンプレート中の`{{key}}`を翻訳メッセージに置き換える. `{{lang}}`は言語タグになる.
+pub fn render_template(locale: Locale, template: &str) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let key = rest[start + 2..start + end].trim();
+        rendered.push_str(&rest[..start]);
+        match key {
+            "lang" => rendered.push_str(locale.as_str()),
+            key => rendered.push_str(message(locale, key).unwrap_or(key)),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+/// メッセージカタログ. (日本語, 英語)
+fn catalog(key: &str) -> Option<(&'static str, &'static str)> {
+    let entry = match key {
+        // エラーコードごとのタイトル. キーはErrorCode::message_key.
+        "error.unexpected_error" => ("内部エラー", "Internal error"),
+        "error.authentication_error" => ("認証エラー", "Authentication failed"),
+        "error.authorization_error" => ("認可エラー", "Forbidden"),
+        "error.workflow_exception" => {
+            ("処理を続行できません", "The request could not be completed")
+        }
+
+        // OpenID Connect
+        "error.oidc.state_missing" => (
+            "state値がcookieに含まれていない",
+            "The state cookie is missing",
+        ),
+        "error.oidc.state_mismatch" => ("不正なstate値", "The state does not match"),
+        "error.oidc.invalid_grant" => (
+            "認可コードが無効です. もう一度ログインしてください",
+            "The authorization code is invalid. Please sign in again",
+        ),
+        "error.oidc.invalid_client" => (
+            "認証サーバーがこのアプリケーションを認証できませんでした",
+            "The identity provider rejected this application",
+        ),
+        "error.oidc.token_error" => (
+            "認証サーバーがリクエストを拒否しました",
+            "The identity provider rejected the request",
+        ),
+        "error.oidc.unavailable" => (
+            "認証サーバーの情報を取得できていません",
+            "The identity provider is not available yet",
+        ),
+
+        // 楽観的排他制御
+        "error.version.precondition_required" => (
+            "If-Matchヘッダで更新対象のバージョンを指定してください",
+            "The If-Match header is required",
+        ),
+        "error.version.precondition_failed" => (
+            "データが更新されています. 最新のデータを取得し直してください",
+            "The resource has been modified. Please reload it",
+        ),
+        "error.version.conflict" => (
+            "同時に他の更新が行われました. もう一度お試しください",
+            "The resource was modified concurrently. Please try again",
+        ),
+
+        // サンプル
+        "error.example.note_not_found" => ("メモが見つかりません", "The note was not found"),
+
+        // ログイン画面
+        "login.title" => ("ログイン", "Sign in"),
+        "login.google" => ("google でログイン", "Sign in with Google"),
+
+        _ => return None,
+    };
+    Some(entry)
+}
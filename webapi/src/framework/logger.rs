@@ -1,11 +1,24 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::OnceLock};
 
 use super::ReqScopedState;
 use axum::{
     async_trait, extract,
     http::{request::Parts, StatusCode},
 };
+use chrono::Utc;
 use serde_json::{json, Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Span, Subscriber,
+};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
 
 pub trait LoggerInterface {
     fn info(&self, item: &str);
@@ -14,58 +27,67 @@ pub trait LoggerInterface {
     fn debug(&self, item: &str);
 }
 
+/// リクエストごとのロガー. リクエストのspanの中でtracingのイベントを発行する.
 #[derive(Clone, Debug)]
-
-pub struct Logger(Map<String, Value>);
+pub struct Logger(Span);
 
 impl Logger {
     pub fn new(ctx: &ReqScopedState, req: &extract::Request, remote_addr: &SocketAddr) -> Self {
-        let method = req.method();
-        let uri = req.uri();
-        let mut pairs = vec![
-            ("req_id", ctx.req_id.to_string()),
-            ("timestamp", ctx.ts.to_utc().to_rfc3339()),
-            ("uri", uri.to_string()),
-            ("method", method.to_string()),
-            ("remote_addr", remote_addr.to_string()),
-        ];
-
-        let header_keys = vec!["user-agent", "cookie"];
-
-        for key in header_keys {
-            if let Some(v) = req.headers().get(key) {
-                pairs.push((key, v.to_str().unwrap_or("parse error").to_string()));
-            }
-        }
+        let header = |key| {
+            req.headers()
+                .get(key)
+                .map(|v| v.to_str().unwrap_or("parse error"))
+        };
+
+        let span = tracing::info_span!(
+            "request",
+            req_id = %ctx.req_id,
+            request_id = %ctx.request_id,
+            trace_id = %ctx.trace.trace_id,
+            span_id = %ctx.trace.span_id,
+            parent_span_id = ctx.trace.parent_span_id.as_deref(),
+            timestamp = %ctx.ts.to_utc().to_rfc3339(),
+            uri = %req.uri(),
+            method = %req.method(),
+            remote_addr = %remote_addr,
+            "user-agent" = header("user-agent"),
+            cookie = header("cookie"),
+        );
 
-        Logger(Map::from_iter(
-            pairs.iter().map(|(k, v)| (k.to_string(), json!(v))),
-        ))
+        Logger(span)
     }
 
-    fn log(mut map: Map<String, Value>, level: LogLevel, item: &str) {
-        map.insert("log_level".to_string(), json!(level.to_string()));
-        map.insert("message".to_string(), json!(item.to_string()));
+    /// リクエストのspan. ハンドラのfutureをこのspanでinstrumentする.
+    pub fn span(&self) -> &Span {
+        &self.0
+    }
 
-        let tmp: Value = map.into();
-        println!("{tmp}")
+    fn log(&self, level: LogLevel, item: &str) {
+        let _entered = self.0.enter();
+        match level {
+            LogLevel::Info => tracing::info!("{item}"),
+            LogLevel::Warning => tracing::warn!("{item}"),
+            LogLevel::Danger => tracing::error!("{item}"),
+            LogLevel::Debug => tracing::debug!("{item}"),
+            LogLevel::Trace => tracing::trace!("{item}"),
+        }
     }
 }
 
 impl LoggerInterface for Logger {
     fn info(&self, item: &str) {
-        Logger::log(self.0.clone(), LogLevel::Info, item)
+        self.log(LogLevel::Info, item)
     }
     fn warning(&self, item: &str) {
-        Logger::log(self.0.clone(), LogLevel::Warning, item)
+        self.log(LogLevel::Warning, item)
     }
 
     fn danger(&self, item: &str) {
-        Logger::log(self.0.clone(), LogLevel::Danger, item)
+        self.log(LogLevel::Danger, item)
     }
 
     fn debug(&self, item: &str) {
-        Logger::log(self.0.clone(), LogLevel::Debug, item)
+        self.log(LogLevel::Debug, item)
     }
 }
 
@@ -82,12 +104,189 @@ impl<S: Send + Sync> extract::FromRequestParts<S> for Logger {
     }
 }
 
+/// 実行中にログレベルを変更するためのハンドル.
+static FILTER_HANDLE: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// tracingのsubscriberを登録する. `level`は`EnvFilter`の書式で、`config::load`で検証済みの値を渡す.
+pub fn init(level: &str) {
+    let (filter, handle) = reload::Layer::new(EnvFilter::new(level));
+    let _ = FILTER_HANDLE.set(handle);
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(JsonLayer::stdout())
+        .init();
+}
+
+/// ログレベルを差し替える. `init`の前に呼んだ場合は何もしない.
+pub fn set_level(level: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(level).map_err(|e| e.to_string())?;
+    match FILTER_HANDLE.get() {
+        Some(handle) => handle.reload(filter).map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
+}
+
+/// `Logger`と同じJSON形式で1イベント1行を標準出力に書き出すlayer.
+///
+/// イベントを囲むspanのフィールド(`req_id`など)をルートから順に展開し、
+/// `log_level`と`message`を加える. このクレート以外から来たイベントには`target`も付ける.
+///
+/// `transaction.outcome`のようにドットを含むフィールドは入れ子のオブジェクトになる.
+/// `JSON_FIELDS`のフィールドはJSONとして解釈し、文字列ではなくその値を書き出す.
+pub struct JsonLayer {
+    write: Box<dyn Fn(String) + Send + Sync>,
+}
+
+/// JSONにシリアライズした文字列として記録されるフィールド. `Panic`のバックトレースなど.
+const JSON_FIELDS: [&str; 2] = ["backtrace", "error_chain"];
+
+impl JsonLayer {
+    pub fn stdout() -> Self {
+        Self {
+            write: Box::new(|line| println!("{line}")),
+        }
+    }
+
+    /// 書き出した行を`lines`に溜める.
+    #[cfg(test)]
+    pub(crate) fn capture(lines: std::sync::Arc<std::sync::Mutex<Vec<String>>>) -> Self {
+        Self {
+            write: Box::new(move |line| lines.lock().unwrap().push(line)),
+        }
+    }
+}
+
+/// spanに記録されたフィールド. spanのextensionsに格納する.
+struct SpanFields(Map<String, Value>);
+
+impl<S> Layer<S> for JsonLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut JsonVisitor(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut map = Map::new();
+
+        match ctx.event_scope(event) {
+            Some(scope) => {
+                for span in scope.from_root() {
+                    if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                        map.extend(fields.clone());
+                    }
+                }
+            }
+            None => {
+                map.insert("timestamp".to_string(), json!(Utc::now().to_rfc3339()));
+            }
+        }
+
+        let normalized = event.normalized_metadata();
+        let metadata = normalized.as_ref().unwrap_or_else(|| event.metadata());
+
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        // logクレートから来たイベントの`log.target`など
+        fields.remove("log");
+        map.extend(fields);
+
+        if !metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            map.insert("target".to_string(), json!(metadata.target()));
+        }
+        map.insert(
+            "log_level".to_string(),
+            json!(LogLevel::from(metadata.level()).to_string()),
+        );
+
+        (self.write)(Value::from(map).to_string())
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl JsonVisitor<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        let mut path = field.name().split('.');
+        let last = path.next_back().unwrap_or_default();
+        let mut map = &mut *self.0;
+        for segment in path {
+            let entry = map
+                .entry(segment)
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !entry.is_object() {
+                *entry = Value::Object(Map::new());
+            }
+            map = entry.as_object_mut().expect("直前にオブジェクトにしている");
+        }
+        map.insert(last.to_string(), value);
+    }
+}
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.insert(field, json!(format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let parsed = JSON_FIELDS
+            .contains(&field.name())
+            .then(|| serde_json::from_str(value).ok())
+            .flatten();
+        self.insert(field, parsed.unwrap_or_else(|| json!(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, json!(value));
+    }
+}
+
 #[derive(Clone, Debug)]
 enum LogLevel {
     Info,
     Warning,
     Danger,
     Debug,
+    Trace,
+}
+
+impl From<&Level> for LogLevel {
+    fn from(level: &Level) -> Self {
+        match *level {
+            Level::ERROR => LogLevel::Danger,
+            Level::WARN => LogLevel::Warning,
+            Level::INFO => LogLevel::Info,
+            Level::DEBUG => LogLevel::Debug,
+            Level::TRACE => LogLevel::Trace,
+        }
+    }
 }
 
 impl std::fmt::Display for LogLevel {
@@ -97,7 +296,54 @@ impl std::fmt::Display for LogLevel {
             LogLevel::Warning => "warning",
             LogLevel::Danger => "danger",
             LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
         };
         write!(f, "{}", item)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// イベントのフィールドを`JsonVisitor`で記録する.
+    struct Capture(Arc<Mutex<Vec<Map<String, Value>>>>);
+
+    impl<S: Subscriber> Layer<S> for Capture {
+        fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+            let mut fields = Map::new();
+            event.record(&mut JsonVisitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    fn capture(f: impl FnOnce()) -> Value {
+        let events = Arc::new(Mutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry().with(Capture(events.clone()));
+        tracing::subscriber::with_default(subscriber, f);
+        let mut events = events.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        Value::Object(events.pop().unwrap())
+    }
+
+    #[test]
+    fn nests_dotted_fields() {
+        let event = capture(|| {
+            tracing::info!(
+                transaction.outcome = "commit",
+                transaction.duration_ms = 3_u64,
+                req_id = "01",
+                "done"
+            )
+        });
+        assert_eq!(
+            event,
+            json!({
+                "transaction": { "outcome": "commit", "duration_ms": 3 },
+                "req_id": "01",
+                "message": "done",
+            })
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{self, ConnectInfo, MatchedPath, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sea_orm::DatabaseConnection;
+use subtle::ConstantTimeEq;
+
+use super::{server::UnixPeer, AppState};
+
+/// Prometheusのメトリクス. `AppState`に持たせて各所から記録する.
+#[derive(Clone)]
+pub struct Metrics(Arc<Inner>);
+
+struct Inner {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    db_pool_connections: IntGaugeVec,
+    oidc_logins: IntCounterVec,
+    session_lookups: IntCounterVec,
+    outbound_requests: IntCounterVec,
+    outbound_request_duration: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("webapi".to_string()), None)
+            .expect("prefixは正しい形式であるべき");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTPリクエスト数"),
+            &["method", "route", "status"],
+        )
+        .expect("メトリクスの定義は正しいはず");
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTPリクエストの処理時間"),
+            &["method", "route", "status"],
+        )
+        .expect("メトリクスの定義は正しいはず");
+        let db_pool_connections = IntGaugeVec::new(
+            Opts::new("db_pool_connections", "DBコネクションプールの接続数"),
+            &["pool", "state"],
+        )
+        .expect("メトリクスの定義は正しいはず");
+        let oidc_logins = IntCounterVec::new(
+            Opts::new("oidc_logins_total", "OpenID Connectでのログイン試行数"),
+            &["result", "reason"],
+        )
+        .expect("メトリクスの定義は正しいはず");
+        let session_lookups = IntCounterVec::new(
+            Opts::new("session_lookups_total", "セッションストアの検索数"),
+            &["result"],
+        )
+        .expect("メトリクスの定義は正しいはず");
+        let outbound_requests = IntCounterVec::new(
+            Opts::new("outbound_requests_total", "外部へのHTTPリクエスト数"),
+            &["host", "method", "status"],
+        )
+        .expect("メトリクスの定義は正しいはず");
+        let outbound_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "outbound_request_duration_seconds",
+                "外部へのHTTPリクエストの所要時間",
+            ),
+            &["host", "method", "status"],
+        )
+        .expect("メトリクスの定義は正しいはず");
+
+        for collector in [
+            Box::new(http_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(http_request_duration.clone()),
+            Box::new(db_pool_connections.clone()),
+            Box::new(oidc_logins.clone()),
+            Box::new(session_lookups.clone()),
+            Box::new(outbound_requests.clone()),
+            Box::new(outbound_request_duration.clone()),
+        ] {
+            registry
+                .register(collector)
+                .expect("メトリクス名は重複しないはず");
+        }
+
+        Self(Arc::new(Inner {
+            registry,
+            http_requests,
+            http_request_duration,
+            db_pool_connections,
+            oidc_logins,
+            session_lookups,
+            outbound_requests,
+            outbound_request_duration,
+        }))
+    }
+
+    pub fn login_succeeded(&self) {
+        self.0.oidc_logins.with_label_values(&["success", ""]).inc();
+    }
+
+    pub fn login_failed(&self, reason: &str) {
+        self.0
+            .oidc_logins
+            .with_label_values(&["failure", reason])
+            .inc();
+    }
+
+    pub fn session_lookup(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.0.session_lookups.with_label_values(&[result]).inc();
+    }
+
+    /// 外部へのHTTPリクエスト. 再試行した場合は1回ずつ記録する.
+    pub fn outbound_request(&self, host: &str, method: &str, status: &str, elapsed: Duration) {
+        self.0
+            .outbound_requests
+            .with_label_values(&[host, method, status])
+            .inc();
+        self.0
+            .outbound_request_duration
+            .with_label_values(&[host, method, status])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// テキスト形式で書き出す. コネクションプールの状態はこの時点の値を読む.
+    fn render(&self, state: &AppState) -> Result<String, prometheus::Error> {
+        self.record_pool("primary", state.db_client.primary());
+        if let Some(replica) = state.db_client.replica() {
+            self.record_pool("replica", replica);
+        }
+
+        TextEncoder::new().encode_to_string(&self.0.registry.gather())
+    }
+
+    fn record_pool(&self, name: &str, db: &DatabaseConnection) {
+        let pool = db.get_postgres_connection_pool();
+        let size = pool.size() as i64;
+        let idle = pool.num_idle() as i64;
+        let gauge = &self.0.db_pool_connections;
+        gauge.with_label_values(&[name, "idle"]).set(idle);
+        gauge.with_label_values(&[name, "in_use"]).set(size - idle);
+        gauge
+            .with_label_values(&[name, "max"])
+            .set(pool.options().get_max_connections() as i64);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// リクエスト数と処理時間をルートとステータスごとに記録するミドルウェア.
+pub async fn track(
+    State(state): State<AppState>,
+    req: extract::Request,
+    next: middleware::Next,
+) -> Response {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let res = next.run(req).await;
+
+    let status = res.status().as_u16().to_string();
+    let labels = [method.as_str(), route.as_str(), status.as_str()];
+    let metrics = &state.metrics.0;
+    metrics.http_requests.with_label_values(&labels).inc();
+    metrics
+        .http_request_duration
+        .with_label_values(&labels)
+        .observe(start.elapsed().as_secs_f64());
+
+    res
+}
+
+/// `/metrics`. `METRICS_TOKEN`が設定されていればBearerトークンで許可する.
+/// 未設定の場合は`metrics_allow_loopback`を有効にしたときだけループバックから許可し、それ以外は拒否する.
+pub async fn handler(
+    State(state): State<AppState>,
+    ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
+    unix_peer: Option<Extension<UnixPeer>>,
+    headers: HeaderMap,
+) -> Response {
+    let allowed = match &state.env.metrics_token {
+        Some(token) => headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| token_matches(v, token.expose())),
+        // Unixドメインソケットはプロキシ経由の接続を区別できないので許可しない
+        None => {
+            state.env.metrics_allow_loopback
+                && unix_peer.is_none()
+                && remote_addr.ip().is_loopback()
+        }
+    };
+
+    if !allowed {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    match state.metrics.render(&state) {
+        Ok(body) => ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// 一致するまでの時間からトークンを推測されないよう、定数時間で比較する.
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(token_matches("secret", "secret"));
+        assert!(!token_matches("secreT", "secret"));
+        assert!(!token_matches("secret2", "secret"));
+        assert!(!token_matches("", "secret"));
+    }
+}
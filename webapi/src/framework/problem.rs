@@ -0,0 +1,275 @@
+use axum::{
+    extract,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use super::{
+    i18n::{self, Locale, MessageKey},
+    system::ErrorCode,
+    ReqScopedState,
+};
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// RFC 7807の問題詳細. `AppError`はこれをレスポンスのextensionsに入れて返し、
+/// `render`ミドルウェアが`instance`と`req_id`を補い、リクエストの言語でクライアントに合う形式で書き出す.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// 機械可読なエラーコード. バリアントごとに固定.
+    pub code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub req_id: Option<String>,
+    #[serde(skip)]
+    message_key: &'static str,
+    #[serde(skip)]
+    detail_key: Option<MessageKey>,
+    #[serde(skip)]
+    locale: Locale,
+}
+
+impl ProblemDetails {
+    /// `detail`はそのまま出す. 翻訳する場合は`with_detail_key`を使う.
+    pub fn new(error_code: ErrorCode, status: StatusCode, detail: Option<String>) -> Self {
+        let mut problem = Self {
+            type_uri: format!("urn:webapi:problem:{}", error_code.code),
+            title: String::new(),
+            status: status.as_u16(),
+            detail,
+            instance: None,
+            code: error_code.code,
+            req_id: None,
+            message_key: error_code.message_key,
+            detail_key: None,
+            locale: Locale::default(),
+        };
+        problem.localize(Locale::default());
+        problem
+    }
+
+    /// `detail`を翻訳メッセージにする. 描画時にリクエストの言語のメッセージに置き換える.
+    pub fn with_detail_key(mut self, key: MessageKey) -> Self {
+        self.detail_key = Some(key);
+        self.localize(self.locale);
+        self
+    }
+
+    /// `title`と`detail`を指定の言語にする.
+    fn localize(&mut self, locale: Locale) {
+        self.locale = locale;
+        self.title = i18n::message(locale, self.message_key)
+            .or_else(|| i18n::message(locale, ErrorCode::WORKFLOW.message_key))
+            .unwrap_or(self.message_key)
+            .to_string();
+        if let Some(key) = self.detail_key {
+            self.detail = Some(
+                i18n::message(locale, key.as_str())
+                    .unwrap_or(key.as_str())
+                    .to_string(),
+            );
+        }
+    }
+
+    pub fn with_req_id(mut self, req_id: String) -> Self {
+        self.req_id = Some(req_id);
+        self
+    }
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn json_response(&self) -> Response {
+        let mut res = (self.status_code(), Json(self)).into_response();
+        res.headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON));
+        res.headers_mut().insert(
+            header::CONTENT_LANGUAGE,
+            HeaderValue::from_static(self.locale.as_str()),
+        );
+        res
+    }
+
+    fn html_response(&self) -> Response {
+        let detail = self
+            .detail
+            .as_deref()
+            .map(|d| format!("<p>{}</p>\n", escape_html(d)))
+            .unwrap_or_default();
+        let req_id = self
+            .req_id
+            .as_deref()
+            .map(|id| format!("<p><small>{}</small></p>\n", escape_html(id)))
+            .unwrap_or_default();
+        let body = format!(
+            "<html lang=\"{lang}\">\n<head><title>{title}</title></head>\n<body>\n<h1>{title}</h1>\n{detail}{req_id}</body>\n</html>\n",
+            lang = self.locale.as_str(),
+            title = escape_html(&self.title),
+        );
+
+        let mut res = (self.status_code(), Html(body)).into_response();
+        res.headers_mut().insert(
+            header::CONTENT_LANGUAGE,
+            HeaderValue::from_static(self.locale.as_str()),
+        );
+        res
+    }
+}
+
+impl IntoResponse for ProblemDetails {
+    /// `render`を通らない場合でもproblem+jsonとして成立するように書き出す.
+    fn into_response(self) -> Response {
+        let mut res = self.json_response();
+        res.extensions_mut().insert(self);
+        res
+    }
+}
+
+/// エラーレスポンスを書き出すミドルウェア. ブラウザ(`text/html`を優先するクライアント)にはHTMLを返す.
+pub async fn render(req: extract::Request, next: middleware::Next) -> Response {
+    let prefers_html = prefers_html(req.headers());
+    let instance = req.uri().path().to_string();
+    let ctx = req.extensions().get::<ReqScopedState>();
+    let req_id = ctx.map(|ctx| ctx.req_id.to_string());
+    let locale = ctx
+        .map(|ctx| ctx.locale)
+        .unwrap_or_else(|| Locale::from_headers(req.headers()));
+
+    let mut res = next.run(req).await;
+    let Some(mut problem) = res.extensions_mut().remove::<ProblemDetails>() else {
+        return res;
+    };
+    problem.instance.get_or_insert(instance);
+    if problem.req_id.is_none() {
+        problem.req_id = req_id;
+    }
+    problem.localize(locale);
+
+    let mut rendered = if prefers_html {
+        problem.html_response()
+    } else {
+        problem.json_response()
+    };
+
+    // cookieなど元のレスポンスに付いていたヘッダは引き継ぐ
+    let mut headers = std::mem::take(res.headers_mut());
+    headers.remove(header::CONTENT_TYPE);
+    headers.remove(header::CONTENT_LENGTH);
+    for (name, value) in headers.iter() {
+        rendered.headers_mut().append(name, value.clone());
+    }
+    // 外側のミドルウェアが使う`UnexpectedError`なども引き継ぐ
+    *rendered.extensions_mut() = std::mem::take(res.extensions_mut());
+
+    rendered
+}
+
+/// `Accept`でHTMLがJSONより高く評価されているか. `*/*`だけの場合はJSONとみなす.
+fn prefers_html(headers: &HeaderMap) -> bool {
+    let mut html = 0.0_f32;
+    let mut json = 0.0_f32;
+
+    for item in headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+    {
+        let mut params = item.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default().to_ascii_lowercase();
+        let q = params
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        match media_type.as_str() {
+            "text/html" | "application/xhtml+xml" => html = html.max(q),
+            "application/json" | PROBLEM_JSON => json = json.max(q),
+            _ => {}
+        }
+    }
+
+    html > json
+}
+
+fn escape_html(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn prefers_html_for_browsers() {
+        assert!(prefers_html(&accept(
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"
+        )));
+        assert!(prefers_html(&accept("application/json;q=0.5, text/html")));
+    }
+
+    #[test]
+    fn prefers_json_otherwise() {
+        assert!(!prefers_html(&HeaderMap::new()));
+        assert!(!prefers_html(&accept("*/*")));
+        assert!(!prefers_html(&accept(
+            "application/problem+json, text/html;q=0.9"
+        )));
+        assert!(!prefers_html(&accept("text/html, application/json")));
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn localizes_only_detail_keys() {
+        let status = StatusCode::CONFLICT;
+        let mut keyed = ProblemDetails::new(ErrorCode::WORKFLOW, status, None)
+            .with_detail_key(MessageKey::new("error.version.conflict"));
+        keyed.localize(Locale::En);
+        assert_eq!(
+            keyed.detail.as_deref(),
+            Some("The resource was modified concurrently. Please try again")
+        );
+
+        // 文言がたまたまキーと同じでも置き換えない
+        let mut text = ProblemDetails::new(
+            ErrorCode::WORKFLOW,
+            status,
+            Some("error.version.conflict".to_string()),
+        );
+        text.localize(Locale::En);
+        assert_eq!(text.detail.as_deref(), Some("error.version.conflict"));
+    }
+}
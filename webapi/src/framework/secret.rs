@@ -0,0 +1,66 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+use zeroize::Zeroize;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// 秘密情報. `Debug`や`Display`では値を出力せず、drop時にメモリをゼロ埋めする.
+///
+/// 値が必要な箇所でだけ`expose`で取り出す. ゼロ埋めされるのはこの値と設定の読み込み中に作った複製までで、
+/// プロセスの環境変数そのものや`expose`した先で作られた複製は残る.
+#[derive(Clone, Default)]
+pub struct Secret<T: Zeroize>(T);
+
+impl<T: Zeroize> Secret<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T: Zeroize> Drop for Secret<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl<T: Zeroize> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T: Zeroize> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<'de, T: Zeroize + Deserialize<'de>> Deserialize<'de> for Secret<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        T::deserialize(deserializer).map(Secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn does_not_format_the_value() {
+        let secret = Secret::new("secret-value".to_string());
+        assert!(!format!("{secret:?}").contains("secret-value"));
+        assert!(!format!("{secret}").contains("secret-value"));
+        assert_eq!(secret.expose(), "secret-value");
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    fs::File,
+    future::Future,
+    io::{self, BufReader},
+    net::SocketAddr,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use arc_swap::ArcSwap;
+use axum::{extract::ConnectInfo, Extension, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{
+        conn::auto,
+        graceful::{GracefulShutdown, Watcher},
+    },
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    signal::unix::{signal, SignalKind},
+    task::JoinSet,
+};
+use tokio_rustls::{
+    rustls::{
+        crypto::ring,
+        pki_types::{CertificateDer, PrivateKeyDer},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+use tower::ServiceExt;
+
+use super::env::Env;
+
+/// 証明書ファイルの更新を確認する間隔
+const CERT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// TLSのハンドシェイクを待つ時間. 過ぎたら接続を閉じる.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// acceptが接続以外の理由で失敗した場合(ファイルディスクリプタの枯渇など)に待つ時間
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Unixドメインソケットの接続に入れる`ConnectInfo`. 接続元のアドレスは分からないので未指定とし、
+/// 前段のプロキシ経由の接続をループバックとみなさないようにする.
+const UNIX_PEER_ADDR: SocketAddr =
+    SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED), 0);
+
+/// Unixドメインソケットから受け付けたリクエストのextensionsに入る.
+#[derive(Clone, Copy, Debug)]
+pub struct UnixPeer;
+
+/// 待ち受け方. `Env`の`host`/`port`、`unix_socket`、`tls_*`から決まる.
+pub enum Listener {
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub async fn bind(env: &Env) -> io::Result<Self> {
+        if let Some(path) = &env.unix_socket {
+            remove_stale_socket(Path::new(path))?;
+            let listener = UnixListener::bind(path)?;
+            tracing::info!("unix:{path}で待ち受ける");
+            return Ok(Listener::Unix(listener, PathBuf::from(path)));
+        }
+
+        let listener = TcpListener::bind((env.host.as_str(), env.port)).await?;
+        let addr = listener.local_addr()?;
+
+        match (&env.tls_cert_path, &env.tls_key_path) {
+            (Some(cert), Some(key)) => {
+                let acceptor = tls_acceptor(PathBuf::from(cert), PathBuf::from(key))?;
+                tracing::info!("https://{addr}で待ち受ける");
+                Ok(Listener::Tls(listener, acceptor))
+            }
+            _ => {
+                tracing::info!("http://{addr}で待ち受ける");
+                Ok(Listener::Tcp(listener))
+            }
+        }
+    }
+}
+
+/// acceptした接続. TLSのハンドシェイクはまだ行っていない.
+/// 前回の起動で残ったソケットファイルを消す. パスの指定を誤って他のファイルを消さないよう、
+/// ソケット以外のファイルがあればエラーにする.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{}はソケットではないので使えない", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    Tls(TcpStream, SocketAddr, TlsAcceptor),
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> io::Result<Accepted> {
+        Ok(match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Accepted::Tcp(stream, addr)
+            }
+            Listener::Tls(listener, acceptor) => {
+                let (stream, addr) = listener.accept().await?;
+                Accepted::Tls(stream, addr, acceptor.clone())
+            }
+            Listener::Unix(listener, _) => Accepted::Unix(listener.accept().await?.0),
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// 接続ごとにHTTP/1.1とHTTP/2のどちらかで`router`を提供する.
+/// `into_make_service_with_connect_info`と同じく`ConnectInfo<SocketAddr>`をextensionsに入れる.
+///
+/// `shutdown`が完了したら新しい接続を受け付けずに、処理中のリクエストを`drain_timeout`まで待つ.
+/// それまでに終わらなかった接続は打ち切る.
+pub async fn serve(
+    listener: Listener,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+    drain_timeout: Duration,
+) -> io::Result<()> {
+    let graceful = GracefulShutdown::new();
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            // 終了した接続のタスクを回収する
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = &mut shutdown => break,
+        };
+        let accepted = match accepted {
+            Ok(accepted) => accepted,
+            // axum::serveと同じく、acceptの失敗ではサーバーを止めない
+            Err(e) if is_connection_error(&e) => continue,
+            Err(e) => {
+                tracing::warn!("接続を受け付けられない: {e}");
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let router = router.clone();
+        let watcher = graceful.watcher();
+
+        match accepted {
+            Accepted::Tcp(stream, addr) => {
+                connections.spawn(serve_connection(stream, addr, router, watcher));
+            }
+            Accepted::Unix(stream) => {
+                let router = router.layer(Extension(UnixPeer));
+                connections.spawn(serve_connection(stream, UNIX_PEER_ADDR, router, watcher));
+            }
+            // ハンドシェイクは接続ごとのタスクで行い、acceptを止めない
+            Accepted::Tls(stream, addr, acceptor) => {
+                connections.spawn(async move {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => serve_connection(stream, addr, router, watcher).await,
+                        Ok(Err(e)) => tracing::debug!("TLSハンドシェイクに失敗した: {addr}: {e}"),
+                        Err(_) => tracing::debug!("TLSハンドシェイクが終わらない: {addr}"),
+                    }
+                });
+            }
+        }
+    }
+
+    drop(listener);
+    tracing::info!(
+        "新しい接続の受け付けを止めた. 処理中の{}接続の終了を待つ",
+        graceful.count()
+    );
+
+    match tokio::time::timeout(drain_timeout, graceful.shutdown()).await {
+        Ok(()) => tracing::info!("全ての接続が終了した"),
+        Err(_) => {
+            tracing::warn!(
+                "{}秒以内に終了しなかった{}接続を打ち切る",
+                drain_timeout.as_secs(),
+                connections.len()
+            );
+            connections.shutdown().await;
+        }
+    }
+
+    Ok(())
+}
+
+/// 接続ごとの失敗で、次のacceptには影響しないもの
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// SIGTERMかSIGINTを受けたら完了する.
+pub async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::warn!("SIGTERMを待ち受けられない: {e}");
+            let _ = tokio::signal::ctrl_c().await;
+            tracing::info!("SIGINTを受けたので終了する");
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("SIGTERMを受けたので終了する"),
+        _ = tokio::signal::ctrl_c() => tracing::info!("SIGINTを受けたので終了する"),
+    }
+}
+
+async fn serve_connection<S>(stream: S, addr: SocketAddr, router: Router, watcher: Watcher)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = router.map_request(move |mut req: hyper::Request<Incoming>| {
+        req.extensions_mut().insert(ConnectInfo(addr));
+        req
+    });
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder
+        .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service));
+    if let Err(e) = watcher.watch(conn).await {
+        tracing::debug!("接続がエラーで終了した: {addr}: {e}");
+    }
+}
+
+/// 証明書ファイルが更新されたら、次のハンドシェイクから新しい証明書を使う.
+fn tls_acceptor(cert: PathBuf, key: PathBuf) -> io::Result<TlsAcceptor> {
+    let resolver = Arc::new(CertResolver(ArcSwap::from_pointee(load_certified_key(
+        &cert, &key,
+    )?)));
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    tokio::spawn(async move {
+        let mut poll = tokio::time::interval(CERT_POLL_INTERVAL);
+        let mut last_modified = (modified(&cert), modified(&key));
+        loop {
+            poll.tick().await;
+            let current = (modified(&cert), modified(&key));
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            match load_certified_key(&cert, &key) {
+                Ok(certified_key) => {
+                    resolver.0.store(Arc::new(certified_key));
+                    tracing::info!("証明書を再読み込みした: {}", cert.display());
+                }
+                Err(e) => tracing::warn!("証明書を再読み込みできないので変更しない: {e}"),
+            }
+        }
+    });
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> io::Result<CertifiedKey> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    };
+
+    let certs =
+        rustls_pemfile::certs(&mut open(cert)?).collect::<Result<Vec<CertificateDer>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::other(format!(
+            "{}に証明書が無い",
+            cert.display()
+        )));
+    }
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut open(key)?)?
+        .ok_or_else(|| io::Error::other(format!("{}に秘密鍵が無い", key.display())))?;
+    let signing_key = ring::sign::any_supported_type(&key).map_err(io::Error::other)?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+#[derive(Debug)]
+struct CertResolver(ArcSwap<CertifiedKey>);
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.load_full())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+    };
+
+    fn unix_listener(name: &str) -> (Listener, PathBuf) {
+        let path = std::env::temp_dir().join(format!("webapi-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        (Listener::Unix(listener, path.clone()), path)
+    }
+
+    async fn get(path: &Path) -> String {
+        let mut stream = UnixStream::connect(path).await.unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        res
+    }
+
+    #[tokio::test]
+    async fn removes_only_stale_sockets() {
+        let path = std::env::temp_dir().join(format!("webapi-stale-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // 閉じてもファイルは残る
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        remove_stale_socket(&path).unwrap();
+
+        std::fs::write(&path, "not a socket").unwrap();
+        let e = remove_stale_socket(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unix_peer_is_not_loopback() {
+        let (listener, path) = unix_listener("peer");
+        let router =
+            Router::new().route(
+                "/",
+                routing::get(
+                    |ConnectInfo(addr): ConnectInfo<SocketAddr>,
+                     unix: Option<Extension<UnixPeer>>| async move {
+                        format!(
+                            "loopback={} unix={}",
+                            addr.ip().is_loopback(),
+                            unix.is_some()
+                        )
+                    },
+                ),
+            );
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            router,
+            async {
+                let _ = stopped.await;
+            },
+            Duration::from_secs(1),
+        ));
+
+        let res = get(&path).await;
+        assert!(res.ends_with("loopback=false unix=true"), "{res}");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn aborts_connections_after_drain_timeout() {
+        struct Dropped(Option<oneshot::Sender<()>>);
+        impl Drop for Dropped {
+            fn drop(&mut self) {
+                let _ = self.0.take().unwrap().send(());
+            }
+        }
+
+        let (listener, path) = unix_listener("drain");
+        let (dropped_tx, dropped_rx) = oneshot::channel();
+        let dropped_tx = Arc::new(std::sync::Mutex::new(Some(dropped_tx)));
+        let (started_tx, started_rx) = oneshot::channel();
+        let started_tx = Arc::new(std::sync::Mutex::new(Some(started_tx)));
+        let router = Router::new().route(
+            "/",
+            routing::get(move || async move {
+                let _guard = Dropped(dropped_tx.lock().unwrap().take());
+                started_tx.lock().unwrap().take().unwrap().send(()).unwrap();
+                std::future::pending::<()>().await
+            }),
+        );
+        let server = tokio::spawn(serve(
+            listener,
+            router,
+            async {
+                let _ = started_rx.await;
+            },
+            Duration::from_millis(100),
+        ));
+
+        let client = tokio::spawn(async move { get(&path).await });
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("drain_timeoutを過ぎても終了しない")
+            .unwrap()
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), dropped_rx)
+            .await
+            .expect("処理中のリクエストが打ち切られていない")
+            .unwrap();
+        client.abort();
+    }
+}
@@ -4,8 +4,9 @@ use reqwest::StatusCode;
 use time::Duration;
 use ulid::Ulid;
 
-use crate::settings::{SESSION_EXPIRATION_HOURS, SESSION_ID_KEY};
+use crate::settings::SESSION_ID_KEY;
 
+use super::config::SessionSettings;
 use super::system::{AuthenticatedUser, Role};
 
 /// セッション
@@ -36,7 +37,7 @@ where
 pub async fn find_session(str: &str) -> Option<Session> {
     if str == "xxx" {
         return Some(Session {
-            session_id: Ulid::from_string(str).unwrap_or(Ulid::new()),
+            session_id: Ulid::from_string(str).unwrap_or_else(|_| Ulid::new()),
             user: AuthenticatedUser {
                 id: "xxx".to_string(),
                 roles: vec![Role::General],
@@ -47,14 +48,22 @@ pub async fn find_session(str: &str) -> Option<Session> {
     None
 }
 
+/// セッションストアに到達できるか確認する. 存在しないセッションを探し、ストアまでの経路を通す.
+///
+/// 今のストアはプロセス内なので失敗しない. 外部に持つようになったら検索の失敗を返す.
+pub async fn ping_store() -> Result<(), String> {
+    let probe = Ulid::new().to_string();
+    find_session(&probe).await;
+    Ok(())
+}
 
-pub fn mk_cookie(session_id: String) -> Cookie<'static> {
+pub fn mk_cookie(session_id: String, settings: &SessionSettings) -> Cookie<'static> {
     let mut c = Cookie::new(SESSION_ID_KEY, session_id);
-    c.set_max_age(Duration::hours(SESSION_EXPIRATION_HOURS));
+    c.set_max_age(Duration::hours(settings.expiration_hours));
     c.set_secure(true);
     c.set_http_only(true);
     c.set_path("/");
     c.set_same_site(SameSite::Lax);
 
     c
-}
\ No newline at end of file
+}
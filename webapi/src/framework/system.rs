@@ -1,25 +1,230 @@
-use super::logger::{Logger, LoggerInterface};
+use super::{
+    error_report::UnexpectedError, i18n::MessageKey, logger::Logger, problem::ProblemDetails,
+};
 use axum::response::{IntoResponse, Response};
+use backtrace::{Backtrace, BacktraceFrame};
 use reqwest::StatusCode;
-use std::{backtrace::Backtrace, fmt::Debug};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        OnceLock,
+    },
+};
 use ulid::Ulid;
 
-pub struct Panic(String, Backtrace);
+#[derive(Debug)]
+pub struct Panic {
+    message: String,
+    /// 取得していなければ`None`. シンボルの解決はログに出す時まで遅らせる.
+    backtrace: Option<Box<Backtrace>>,
+    /// `source()`を辿ったエラーのメッセージ. 原因に近いものほど後ろ.
+    sources: Vec<String>,
+}
 
 impl Panic {
     pub fn new<T: ToString>(msg: T) -> Self {
-        Self(msg.to_string(), Backtrace::force_capture())
+        Self {
+            message: msg.to_string(),
+            backtrace: capture_backtrace().map(Box::new),
+            sources: vec![],
+        }
+    }
+
+    /// 取得済みのバックトレースから作る. panic hookの中で使う.
+    pub(super) fn with_backtrace(message: String, backtrace: Option<Backtrace>) -> Self {
+        Self {
+            message,
+            backtrace: backtrace.map(Box::new),
+            sources: vec![],
+        }
+    }
+
+    /// エラーから作る. `source()`の連鎖も記録する.
+    pub fn from_error<E: Error + ?Sized>(e: &E) -> Self {
+        let mut sources = vec![];
+        let mut source = e.source();
+        while let Some(s) = source {
+            sources.push(s.to_string());
+            source = s.source();
+        }
+
+        Self {
+            message: e.to_string(),
+            backtrace: capture_backtrace().map(Box::new),
+            sources,
+        }
+    }
+
+    /// このクレートのフレームだけに絞ったバックトレース. 取得していなければ空.
+    fn frames(&self) -> Vec<Frame> {
+        self.backtrace
+            .as_deref()
+            .map(crate_frames)
+            .unwrap_or_default()
+    }
+
+    /// バックトレースと`source()`の連鎖をJSONの配列としてフィールドに載せ、ログに出す.
+    /// `JsonLayer`はこれらのフィールドを文字列ではなく配列として書き出す.
+    fn log(&self, logger: &Logger, frames: &[Frame]) {
+        let backtrace = self.backtrace.is_some().then(|| json!(frames).to_string());
+        let error_chain = (!self.sources.is_empty()).then(|| json!(self.sources).to_string());
+        logger.span().in_scope(|| {
+            tracing::error!(
+                backtrace = backtrace.as_deref(),
+                error_chain = error_chain.as_deref(),
+                "{}",
+                self.message
+            )
+        });
+    }
+}
+
+/// バックトレースを取得するか. `Backtrace::force_capture`は重いので設定で切り替える.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BacktraceCapture {
+    /// 取得しない
+    Off,
+    /// `RUST_BACKTRACE`/`RUST_LIB_BACKTRACE`に従う
+    Env,
+    /// 常に取得する
+    Always,
+}
+
+impl std::str::FromStr for BacktraceCapture {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(BacktraceCapture::Off),
+            "env" => Ok(BacktraceCapture::Env),
+            "always" => Ok(BacktraceCapture::Always),
+            _ => Err(format!("off, env, alwaysのいずれかを指定する: {s}")),
+        }
     }
 }
 
+static BACKTRACE_CAPTURE: AtomicU8 = AtomicU8::new(BacktraceCapture::Always as u8);
+
+pub fn set_backtrace_capture(mode: BacktraceCapture) {
+    BACKTRACE_CAPTURE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub(super) fn capture_backtrace() -> Option<Backtrace> {
+    let capture = match BACKTRACE_CAPTURE.load(Ordering::Relaxed) {
+        m if m == BacktraceCapture::Off as u8 => false,
+        m if m == BacktraceCapture::Env as u8 => backtrace_enabled_by_env(),
+        _ => true,
+    };
+    capture.then(Backtrace::new_unresolved)
+}
+
+/// `std::backtrace::Backtrace::capture`と同じく`RUST_LIB_BACKTRACE`、`RUST_BACKTRACE`の順に見る.
+fn backtrace_enabled_by_env() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        std::env::var("RUST_LIB_BACKTRACE")
+            .or_else(|_| std::env::var("RUST_BACKTRACE"))
+            .is_ok_and(|v| v != "0")
+    })
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Frame {
+    pub function: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<u32>,
+}
+
+/// バックトレースを取る処理自体の関数. これらのフレームは出力しない.
+const CAPTURING_FUNCTIONS: [&str; 3] = [
+    concat!(module_path!(), "::capture_backtrace"),
+    concat!(module_path!(), "::Panic::"),
+    concat!(
+        env!("CARGO_CRATE_NAME"),
+        "::framework::catch_panic::install_hook"
+    ),
+];
+
+/// シンボルを解決し、このクレートのフレームだけを返す.
+fn crate_frames(backtrace: &Backtrace) -> Vec<Frame> {
+    let mut backtrace = backtrace.clone();
+    backtrace.resolve();
+
+    let crate_prefix = concat!(env!("CARGO_CRATE_NAME"), "::");
+    backtrace
+        .frames()
+        .iter()
+        .flat_map(BacktraceFrame::symbols)
+        .filter_map(|symbol| {
+            // `{:#}`はシンボル名の末尾のハッシュを除く
+            Some(Frame {
+                function: format!("{:#}", symbol.name()?),
+                file: symbol.filename().map(|file| file.display().to_string()),
+                line: symbol.lineno(),
+                column: symbol.colno(),
+            })
+        })
+        .filter(|f| f.function.contains(crate_prefix))
+        .filter(|f| {
+            !CAPTURING_FUNCTIONS
+                .iter()
+                .any(|c| f.function.starts_with(c))
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub enum AppError {
     /// 業務上xxxなはずだからunwrapする時に使う
-    Unexpected(Logger, String, String, Backtrace),
+    Unexpected(Logger, Panic, String),
     AuthenticationError,
-    AutorizationError(String),
+    AutorizationError(MessageKey),
     /// ワークフローの最中に発生したエラー
-    WorkflowException(StatusCode, String),
+    WorkflowException(StatusCode, MessageKey),
+    /// `DomainError`から変換されたエラー. コードとステータスはderive時の属性で決まる.
+    DomainException(ErrorCode, String),
+}
+
+/// エラーコードの登録情報. `#[derive(DomainError)]`がバリアントごとに生成する.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorCode {
+    /// 機械可読なエラーコード. クライアントが分岐に使うので変更しないこと.
+    pub code: &'static str,
+    pub status: u16,
+    /// 翻訳メッセージのキー
+    pub message_key: &'static str,
+}
+
+impl ErrorCode {
+    pub const UNEXPECTED: ErrorCode =
+        ErrorCode::new("unexpected_error", 500, "error.unexpected_error");
+    pub const AUTHENTICATION: ErrorCode =
+        ErrorCode::new("authentication_error", 401, "error.authentication_error");
+    pub const AUTHORIZATION: ErrorCode =
+        ErrorCode::new("authorization_error", 403, "error.authorization_error");
+    pub const WORKFLOW: ErrorCode =
+        ErrorCode::new("workflow_exception", 500, "error.workflow_exception");
+
+    pub const fn new(code: &'static str, status: u16, message_key: &'static str) -> Self {
+        Self {
+            code,
+            status,
+            message_key,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
 }
 
 pub trait IntoAppError: Sized {
@@ -28,36 +233,128 @@ pub trait IntoAppError: Sized {
 
 impl IntoAppError for Panic {
     fn into_app_error(self, l: Logger, req_id: &Ulid) -> AppError {
-        AppError::Unexpected(l, self.0, req_id.to_string(), self.1)
+        AppError::Unexpected(l, self, req_id.to_string())
     }
 }
 
+/// ドメインごとのエラー. 通常は`#[derive(DomainError)]`で実装する.
 pub trait DomainError: IntoAppError {
     fn from_panic(e: Panic) -> Self
     where
         Self: Sized;
+
+    fn error_code(&self) -> ErrorCode;
+}
+
+pub use webapi_macros::DomainError;
+// derive(DomainError)が生成するコードから使う
+#[doc(hidden)]
+pub use inventory;
+
+/// エラーコードの登録. `#[derive(DomainError)]`がバリアントごとに登録し、型をまたいだ重複の検出に使う.
+#[derive(Debug)]
+pub struct RegisteredErrorCode {
+    pub error_code: ErrorCode,
+    /// 定義したバリアント. `{module_path}::{Type}::{Variant}`
+    pub defined_at: &'static str,
+}
+
+inventory::collect!(RegisteredErrorCode);
+
+inventory::submit! {
+    RegisteredErrorCode { error_code: ErrorCode::UNEXPECTED, defined_at: "AppError::Unexpected" }
+}
+inventory::submit! {
+    RegisteredErrorCode { error_code: ErrorCode::AUTHENTICATION, defined_at: "AppError::AuthenticationError" }
+}
+inventory::submit! {
+    RegisteredErrorCode { error_code: ErrorCode::AUTHORIZATION, defined_at: "AppError::AutorizationError" }
+}
+inventory::submit! {
+    RegisteredErrorCode { error_code: ErrorCode::WORKFLOW, defined_at: "AppError::WorkflowException" }
+}
+
+/// 登録されたエラーコードのうち、複数の箇所で定義されているもの. 空でなければ起動しない.
+pub fn duplicate_error_codes() -> Vec<String> {
+    duplicates(inventory::iter::<RegisteredErrorCode>)
+}
+
+fn duplicates<'a>(registered: impl IntoIterator<Item = &'a RegisteredErrorCode>) -> Vec<String> {
+    let mut by_code: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    for r in registered {
+        by_code
+            .entry(r.error_code.code)
+            .or_default()
+            .push(r.defined_at);
+    }
+    by_code
+        .into_iter()
+        .filter(|(_, defined_at)| defined_at.len() > 1)
+        .map(|(code, mut defined_at)| {
+            defined_at.sort();
+            format!(
+                "エラーコード`{code}`が重複している: {}",
+                defined_at.join(", ")
+            )
+        })
+        .collect()
+}
+
+impl AppError {
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            AppError::Unexpected(..) => ErrorCode::UNEXPECTED,
+            AppError::AuthenticationError => ErrorCode::AUTHENTICATION,
+            AppError::AutorizationError(_) => ErrorCode::AUTHORIZATION,
+            AppError::WorkflowException(..) => ErrorCode::WORKFLOW,
+            AppError::DomainException(code, _) => *code,
+        }
+    }
+
+    /// 機械可読なエラーコード. クライアントが分岐に使うので変更しないこと.
+    pub fn code(&self) -> &'static str {
+        self.error_code().code
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::WorkflowException(code, _) => *code,
+            _ => self.error_code().status(),
+        }
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        match self {
-            AppError::AuthenticationError => {
-                (StatusCode::UNAUTHORIZED, "認証エラー").into_response()
+        let error_code = self.error_code();
+        let status = self.status();
+
+        let problem = match self {
+            AppError::AuthenticationError => ProblemDetails::new(error_code, status, None),
+            AppError::AutorizationError(key) | AppError::WorkflowException(_, key) => {
+                ProblemDetails::new(error_code, status, None).with_detail_key(key)
             }
-            AppError::AutorizationError(msg) => (StatusCode::FORBIDDEN, msg).into_response(),
-            AppError::WorkflowException(code, msg) => (code, msg).into_response(),
-
-            AppError::Unexpected(l, msg, req_id, back_trace) => {
-                l.danger(&msg);
-                println!("{back_trace}",);
-
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("内部エラー: {}", req_id),
-                )
-                    .into_response()
+            AppError::DomainException(_, msg) => ProblemDetails::new(error_code, status, Some(msg)),
+
+            AppError::Unexpected(l, panic, req_id) => {
+                let frames = panic.frames();
+                panic.log(&l, &frames);
+
+                let mut res = ProblemDetails::new(error_code, status, None)
+                    .with_req_id(req_id.clone())
+                    .into_response();
+                // error_report::reportミドルウェアが報告に使う
+                res.extensions_mut().insert(UnexpectedError {
+                    message: panic.message,
+                    backtrace: frames,
+                    error_chain: panic.sources,
+                    req_id,
+                });
+                return res;
             }
-        }
+        };
+
+        problem.into_response()
     }
 }
 
@@ -84,3 +381,93 @@ pub enum Role {
     Admin,
     Master,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::{logger::JsonLayer, ReqScopedState};
+    use serde_json::Value;
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[derive(Debug)]
+    struct Outer(std::io::Error);
+
+    impl std::fmt::Display for Outer {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("outer")
+        }
+    }
+
+    impl Error for Outer {
+        fn source(&self) -> Option<&(dyn Error + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn logs_backtrace_and_error_chain_as_arrays() {
+        let lines = Arc::new(Mutex::new(vec![]));
+        let subscriber = tracing_subscriber::registry().with(JsonLayer::capture(lines.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let req = axum::extract::Request::new(axum::body::Body::empty());
+            let ctx = ReqScopedState::new(Ulid::new(), req.headers());
+            let logger = Logger::new(&ctx, &req, &SocketAddr::from(([127, 0, 0, 1], 0)));
+
+            let outer = Outer(std::io::Error::other("inner"));
+            let panic = Panic {
+                backtrace: Some(Box::new(Backtrace::new_unresolved())),
+                ..Panic::from_error(&outer)
+            };
+            panic.log(&logger, &panic.frames());
+        });
+
+        let lines = lines.lock().unwrap();
+        let line: Value = serde_json::from_str(lines.last().unwrap()).unwrap();
+        let frames = line["backtrace"]
+            .as_array()
+            .expect("backtraceが配列ではない");
+        assert!(!frames.is_empty(), "{line}");
+        for frame in frames {
+            let function = frame["function"].as_str().unwrap();
+            assert!(function.starts_with("webapi::"), "{frame}");
+            assert!(
+                frame["file"].is_string() && frame["line"].is_u64(),
+                "{frame}"
+            );
+        }
+        assert!(frames.iter().any(|f| f["function"]
+            .as_str()
+            .unwrap()
+            .contains("logs_backtrace_and_error_chain_as_arrays")));
+        assert_eq!(line["error_chain"], serde_json::json!(["inner"]));
+    }
+
+    #[test]
+    fn error_codes_are_unique_across_types() {
+        assert_eq!(duplicate_error_codes(), Vec::<String>::new());
+
+        let codes: Vec<&str> = inventory::iter::<RegisteredErrorCode>
+            .into_iter()
+            .map(|r| r.error_code.code)
+            .collect();
+        assert!(codes.contains(&"unexpected_error"));
+        assert!(codes.contains(&"oidc.invalid_grant"));
+    }
+
+    #[test]
+    fn finds_duplicates() {
+        let code = |code, defined_at| RegisteredErrorCode {
+            error_code: ErrorCode::new(code, 400, "error.test"),
+            defined_at,
+        };
+        let registered = [code("a", "B::X"), code("b", "A::Y"), code("a", "A::X")];
+        assert_eq!(
+            duplicates(&registered),
+            ["エラーコード`a`が重複している: A::X, B::X"]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use axum::http::HeaderMap;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+
+/// W3C Trace Context. 上流から`traceparent`を受け取った場合はそのトレースを引き継ぐ.
+#[derive(Clone, Debug)]
+pub struct TraceContext {
+    /// 32桁の16進数
+    pub trace_id: String,
+    /// このサーバーでの処理を表す16桁の16進数
+    pub span_id: String,
+    /// 上流のspan id. 上流が無い場合は`None`.
+    pub parent_span_id: Option<String>,
+    pub trace_flags: u8,
+    pub trace_state: Option<String>,
+}
+
+impl TraceContext {
+    /// ヘッダから引き継ぐ. `traceparent`が無いか不正な場合は新しいトレースを始める.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let span_id = new_span_id();
+
+        let Some((trace_id, parent_span_id, trace_flags)) = headers
+            .get(TRACEPARENT)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent)
+        else {
+            return Self {
+                trace_id: new_trace_id(),
+                span_id,
+                parent_span_id: None,
+                trace_flags: 0,
+                trace_state: None,
+            };
+        };
+
+        // tracestateはtraceparentが有効な場合だけ引き継ぐ
+        let trace_state = headers
+            .get_all(TRACESTATE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        Self {
+            trace_id,
+            span_id,
+            parent_span_id: Some(parent_span_id),
+            trace_flags,
+            trace_state: (!trace_state.is_empty()).then_some(trace_state),
+        }
+    }
+
+    /// 下流に渡す`traceparent`の値. 親はこのサーバーのspan.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id, self.span_id, self.trace_flags
+        )
+    }
+
+    /// 外部へのリクエストにトレースコンテキストを付与する.
+    pub fn inject(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        let builder = builder.header(TRACEPARENT, self.traceparent());
+        match &self.trace_state {
+            Some(trace_state) => builder.header(TRACESTATE, trace_state),
+            None => builder,
+        }
+    }
+}
+
+fn parse_traceparent(value: &str) -> Option<(String, String, u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+
+    let is_hex = |s: &str, len: usize| {
+        s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    };
+    let is_zero = |s: &str| s.bytes().all(|b| b == b'0');
+
+    if !is_hex(version, 2) || version == "ff" {
+        return None;
+    }
+    // version 00 はちょうど4つの要素. 将来のversionは後ろに要素が増えうる.
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || is_zero(trace_id) {
+        return None;
+    }
+    if !is_hex(parent_id, 16) || is_zero(parent_id) {
+        return None;
+    }
+    if !is_hex(flags, 2) {
+        return None;
+    }
+
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_string(), parent_id.to_string(), flags))
+}
+
+fn new_trace_id() -> String {
+    let id = loop {
+        let id = rand::random::<u128>();
+        if id != 0 {
+            break id;
+        }
+    };
+    format!("{id:032x}")
+}
+
+fn new_span_id() -> String {
+    let id = loop {
+        let id = rand::random::<u64>();
+        if id != 0 {
+            break id;
+        }
+    };
+    format!("{id:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_traceparent() {
+        assert_eq!(
+            parse_traceparent(&format!("00-{TRACE_ID}-{PARENT_ID}-01")),
+            Some((TRACE_ID.to_string(), PARENT_ID.to_string(), 1))
+        );
+        // 将来のversionは後ろの要素を無視する
+        assert!(parse_traceparent(&format!("01-{TRACE_ID}-{PARENT_ID}-00-extra")).is_some());
+    }
+
+    #[test]
+    fn rejects_invalid_traceparent() {
+        for value in [
+            format!("ff-{TRACE_ID}-{PARENT_ID}-01"),
+            format!("00-{TRACE_ID}-{PARENT_ID}-01-extra"),
+            format!("00-{}-{PARENT_ID}-01", "0".repeat(32)),
+            format!("00-{TRACE_ID}-{}-01", "0".repeat(16)),
+            format!("00-{}-{PARENT_ID}-01", TRACE_ID.to_uppercase()),
+            format!("00-{TRACE_ID}-{PARENT_ID}-1"),
+            format!("00-{TRACE_ID}-{PARENT_ID}"),
+        ] {
+            assert_eq!(parse_traceparent(&value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn continues_upstream_trace() {
+        let ctx = TraceContext::from_headers(&headers(&[
+            (TRACEPARENT, &format!("00-{TRACE_ID}-{PARENT_ID}-01")),
+            (TRACESTATE, "a=1"),
+            (TRACESTATE, " b=2 "),
+        ]));
+        assert_eq!(ctx.trace_id, TRACE_ID);
+        assert_eq!(ctx.parent_span_id.as_deref(), Some(PARENT_ID));
+        assert_ne!(ctx.span_id, PARENT_ID);
+        assert_eq!(ctx.trace_state.as_deref(), Some("a=1,b=2"));
+        assert_eq!(
+            ctx.traceparent(),
+            format!("00-{TRACE_ID}-{}-01", ctx.span_id)
+        );
+    }
+
+    #[test]
+    fn starts_new_trace_without_valid_traceparent() {
+        let ctx =
+            TraceContext::from_headers(&headers(&[(TRACEPARENT, "garbage"), (TRACESTATE, "a=1")]));
+        assert_ne!(ctx.trace_id, TRACE_ID);
+        assert_eq!(ctx.trace_id.len(), 32);
+        assert_eq!(ctx.span_id.len(), 16);
+        assert_eq!(ctx.parent_span_id, None);
+        assert_eq!(ctx.trace_state, None);
+    }
+}
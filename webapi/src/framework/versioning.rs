@@ -0,0 +1,257 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sea_orm::{
+    sea_query::IntoCondition, ActiveModelTrait, ColumnTrait, ConnectionTrait, DbErr, EntityTrait,
+    IntoActiveModel, ModelTrait, QueryFilter, Value,
+};
+use serde::Serialize;
+use ulid::Ulid;
+
+use super::{
+    i18n::MessageKey,
+    logger::Logger,
+    system::{AppError, IntoAppError, Panic},
+    Meta, NoneOfProps,
+};
+
+/// 楽観的排他制御のためのバージョン列を持つエンティティ.
+/// 列はULIDの文字列で、`update`のたびに新しい値になる.
+pub trait Versioned: EntityTrait {
+    fn version_column() -> Self::Column;
+}
+
+/// `If-Match`で指定されたバージョン. 更新するハンドラで要求する.
+/// `*`や複数のETagは受け付けない.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IfMatch(pub Ulid);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let value = parts.headers.get(header::IF_MATCH).ok_or_else(|| {
+            AppError::WorkflowException(
+                StatusCode::PRECONDITION_REQUIRED,
+                MessageKey::new("error.version.precondition_required"),
+            )
+        })?;
+        // 解析できない値はどのバージョンとも一致しない
+        value
+            .to_str()
+            .ok()
+            .and_then(parse_etag)
+            .map(IfMatch)
+            .ok_or_else(precondition_failed)
+    }
+}
+
+impl IfMatch {
+    /// 読み込んだ時点のバージョンと比べる. 一致しなければ412.
+    pub fn check(&self, current: Ulid) -> Result<(), AppError> {
+        if self.0 == current {
+            Ok(())
+        } else {
+            Err(precondition_failed())
+        }
+    }
+}
+
+/// 強いETagとして比較する. `W/`付きは一致しない.
+fn parse_etag(value: &str) -> Option<Ulid> {
+    let tag = value.trim().strip_prefix('"')?.strip_suffix('"')?;
+    Ulid::from_string(tag).ok()
+}
+
+fn etag(version: Ulid) -> HeaderValue {
+    HeaderValue::from_str(&format!("\"{version}\"")).expect("ULIDはヘッダに使える文字だけからなる")
+}
+
+fn precondition_failed() -> AppError {
+    AppError::WorkflowException(
+        StatusCode::PRECONDITION_FAILED,
+        MessageKey::new("error.version.precondition_failed"),
+    )
+}
+
+/// `data`をJSONで、`version_tag`を`ETag`で返す.
+impl<Data: Serialize> IntoResponse for Meta<'_, Data, NoneOfProps> {
+    fn into_response(self) -> Response {
+        ([(header::ETAG, etag(self.version_tag))], Json(self.data)).into_response()
+    }
+}
+
+/// モデルのバージョン列を読む.
+pub fn version_of<E: Versioned>(model: &E::Model) -> Result<Ulid, Panic> {
+    match model.get(E::version_column()) {
+        Value::String(Some(version)) => Ulid::from_string(&version)
+            .map_err(|e| Panic::new(format!("バージョン列がULIDではない: {version}: {e}"))),
+        other => Err(Panic::new(format!(
+            "バージョン列が文字列ではない: {other:?}"
+        ))),
+    }
+}
+
+/// 新しく作るモデルの最初のバージョンを入れる.
+pub fn init_version<A>(model: &mut A) -> Ulid
+where
+    A: ActiveModelTrait,
+    A::Entity: Versioned,
+{
+    let version = Ulid::new();
+    model.set(A::Entity::version_column(), version.to_string().into());
+    version
+}
+
+/// `expected`のバージョンのままであれば更新し、バージョンを進める.
+/// 読み込んでから更新するまでの間に他の更新が入っていれば`Conflict`.
+pub async fn update<A, C>(
+    db: &C,
+    mut model: A,
+    expected: Ulid,
+) -> Result<<A::Entity as EntityTrait>::Model, UpdateError>
+where
+    A: ActiveModelTrait + Send,
+    A::Entity: Versioned,
+    <A::Entity as EntityTrait>::Model: IntoActiveModel<A>,
+    C: ConnectionTrait,
+{
+    let column = A::Entity::version_column();
+    model.set(column, Ulid::new().to_string().into());
+    let condition = column.eq(expected.to_string()).into_condition();
+
+    match A::Entity::update(model).filter(condition).exec(db).await {
+        Ok(model) => Ok(model),
+        Err(DbErr::RecordNotUpdated) => Err(UpdateError::Conflict),
+        Err(e) => Err(UpdateError::Db(e)),
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    /// 対象が他の更新で変わっていた、または削除されていた
+    Conflict,
+    Db(DbErr),
+}
+
+impl IntoAppError for UpdateError {
+    fn into_app_error(self, l: Logger, req_id: &Ulid) -> AppError {
+        match self {
+            UpdateError::Conflict => AppError::WorkflowException(
+                StatusCode::CONFLICT,
+                MessageKey::new("error.version.conflict"),
+            ),
+            UpdateError::Db(e) => Panic::from_error(&e).into_app_error(l, req_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::example_notes, framework::ReqScopedState};
+    use axum::extract;
+    use migration::{Migrator, MigratorTrait};
+    use sea_orm::{Database, Set};
+    use std::net::SocketAddr;
+
+    async fn if_match(value: Option<&str>) -> Result<IfMatch, AppError> {
+        let mut req = extract::Request::builder();
+        if let Some(value) = value {
+            req = req.header(header::IF_MATCH, value);
+        }
+        let (mut parts, _) = req.body(()).unwrap().into_parts();
+        IfMatch::from_request_parts(&mut parts, &()).await
+    }
+
+    fn status(result: Result<IfMatch, AppError>) -> StatusCode {
+        result.err().map(|e| e.status()).unwrap_or(StatusCode::OK)
+    }
+
+    fn logger() -> Logger {
+        let req = extract::Request::new(axum::body::Body::empty());
+        let ctx = ReqScopedState::new(Ulid::new(), req.headers());
+        Logger::new(&ctx, &req, &SocketAddr::from(([127, 0, 0, 1], 0)))
+    }
+
+    #[test]
+    fn parses_only_strong_etags() {
+        let version = Ulid::new();
+        assert_eq!(parse_etag(&format!("\"{version}\"")), Some(version));
+        assert_eq!(parse_etag(&format!(" \"{version}\" ")), Some(version));
+        assert_eq!(parse_etag(&format!("W/\"{version}\"")), None);
+        assert_eq!(parse_etag(&version.to_string()), None);
+        assert_eq!(parse_etag("*"), None);
+        assert_eq!(parse_etag("\"not-a-ulid\""), None);
+    }
+
+    #[tokio::test]
+    async fn requires_if_match() {
+        let version = Ulid::new();
+        assert_eq!(
+            status(if_match(None).await),
+            StatusCode::PRECONDITION_REQUIRED
+        );
+        assert_eq!(
+            status(if_match(Some("*")).await),
+            StatusCode::PRECONDITION_FAILED
+        );
+
+        let header = format!("\"{version}\"");
+        let if_match = if_match(Some(&header)).await.ok().unwrap();
+        assert_eq!(if_match, IfMatch(version));
+        assert!(if_match.check(version).is_ok());
+        assert_eq!(
+            if_match.check(Ulid::new()).err().unwrap().status(),
+            StatusCode::PRECONDITION_FAILED
+        );
+    }
+
+    /// `DB_URL=... cargo test -p webapi -- --ignored`で実行する. マイグレーションを適用する.
+    #[tokio::test]
+    #[ignore = "DB_URLのPostgreSQLが必要"]
+    async fn update_conflicts_when_the_version_changed() {
+        let url = std::env::var("DB_URL").expect("DB_URLを指定してください");
+        let db = Database::connect(&url).await.unwrap();
+        Migrator::up(&db, None).await.unwrap();
+
+        let mut model = example_notes::ActiveModel {
+            id: Set(Ulid::new().to_string()),
+            body: Set("old".to_string()),
+            ..Default::default()
+        };
+        let version = init_version(&mut model);
+        let created = model.insert(&db).await.unwrap();
+
+        let mut model = created.clone().into_active_model();
+        model.body = Set("new".to_string());
+        let updated = update(&db, model, version).await.ok().unwrap();
+        assert_ne!(updated.version, version.to_string());
+
+        // 読み込んだ後に他の更新が入った
+        let mut model = created.clone().into_active_model();
+        model.body = Set("stale".to_string());
+        let conflict = update(&db, model, version).await.err().unwrap();
+        assert!(matches!(conflict, UpdateError::Conflict));
+        assert_eq!(
+            conflict.into_app_error(logger(), &Ulid::new()).status(),
+            StatusCode::CONFLICT
+        );
+
+        created.delete(&db).await.unwrap();
+    }
+
+    #[test]
+    fn db_errors_are_unexpected() {
+        let error = UpdateError::Db(DbErr::Custom("boom".to_string()));
+        assert_eq!(
+            error.into_app_error(logger(), &Ulid::new()).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
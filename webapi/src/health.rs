@@ -0,0 +1,123 @@
+use crate::framework::{session, AppState};
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing, Json, Router,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// パス
+pub const LIVENESS_PATH: &str = "/healthz";
+pub const READINESS_PATH: &str = "/readyz";
+
+pub fn mk_router() -> Router<AppState> {
+    Router::new()
+        .route(LIVENESS_PATH, routing::get(liveness))
+        .route(READINESS_PATH, routing::get(readiness))
+}
+
+/// プロセスが応答できるかだけを見る. 依存先は確認しない.
+async fn liveness() -> Json<Value> {
+    Json(json!({ "status": Status::Ok }))
+}
+
+/// 依存先ごとの状態を返す. 必須の依存先が1つでも失敗していれば503.
+async fn readiness(State(state): State<AppState>) -> Response {
+    let mut checks = BTreeMap::new();
+
+    checks.insert(
+        "database",
+        Check::required(
+            state
+                .db_client
+                .primary()
+                .ping()
+                .await
+                .map_err(|e| e.to_string()),
+        ),
+    );
+    // replicaが使えなくてもprimaryから読めるので必須ではない
+    if let Some(result) = state.db_client.ping_replica().await {
+        checks.insert(
+            "database_replica",
+            Check::optional(result.map_err(|e| e.to_string())),
+        );
+    }
+    checks.insert("oidc", Check::required(check_oidc(&state)));
+    // 今のセッションストアはプロセス内なので、失敗しても準備中にはしない
+    checks.insert(
+        "session_store",
+        Check::optional(session::ping_store().await),
+    );
+
+    let ready = checks
+        .values()
+        .all(|c| !c.required || matches!(c.status, Status::Ok));
+    let (code, status) = if ready {
+        (StatusCode::OK, Status::Ok)
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Status::Fail)
+    };
+
+    (code, Json(Readiness { status, checks })).into_response()
+}
+
+fn check_oidc(state: &AppState) -> Result<(), String> {
+    // discovery documentは読み込み時に検証済み
+    let Some(provider) = state.discovery.get() else {
+        return state.discovery.status();
+    };
+    if provider.jwk_set.keys.is_empty() {
+        return Err("JWKSが空".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Ok,
+    Fail,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: Status,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+#[derive(Serialize)]
+struct Check {
+    status: Status,
+    required: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn required(result: Result<(), String>) -> Self {
+        Self::new(result, true)
+    }
+
+    fn optional(result: Result<(), String>) -> Self {
+        Self::new(result, false)
+    }
+
+    fn new(result: Result<(), String>, required: bool) -> Self {
+        match result {
+            Ok(()) => Self {
+                status: Status::Ok,
+                required,
+                error: None,
+            },
+            Err(e) => Self {
+                status: Status::Fail,
+                required,
+                error: Some(e),
+            },
+        }
+    }
+}
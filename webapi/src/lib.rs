@@ -1,5 +1,9 @@
+// derive(DomainError)が生成するコードから`::webapi`で参照できるようにする
+extern crate self as webapi;
+
 pub mod db;
 pub mod framework;
+pub mod health;
 pub mod openapi;
 pub mod openid_connect;
 pub mod settings;
@@ -1,4 +1,4 @@
-use std::{error::Error, net::SocketAddr, time::Duration};
+use std::{error::Error, io::Write, net::SocketAddr, time::Duration};
 
 use axum::{
     extract,
@@ -8,45 +8,132 @@ use axum::{
     routing, Router,
 };
 use axum_extra::extract::CookieJar;
-use serde_json::Value;
-use tower_http::{cors::CorsLayer, timeout::TimeoutLayer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::Instrument;
 use ulid::Ulid;
 use webapi::{
     db,
     framework::{
-        self,
-        env::Env,
-        logger::{Logger, LoggerInterface},
+        self, catch_panic,
+        config::{self, Settings},
+        discovery::Discovery,
+        error_report::{self, ErrorReporting, SentryReporter},
+        http_client::HttpClient,
+        i18n,
+        logger::{self, Logger, LoggerInterface},
+        metrics::{self, Metrics},
+        problem,
+        server::{self, Listener},
         session::{mk_cookie, Session},
-        AppState, ReqScopedState,
+        system, AppState, Reloadable, ReqScopedState, REQUEST_ID_HEADER,
     },
+    health,
     openapi::example_route,
     openid_connect,
-    settings::{CORS_ALLOWED_ORIGINS, SESSION_ID_KEY, TIMEOUT_DURATION},
+    settings::SESSION_ID_KEY,
 };
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let env = Env::new();
-    let db_client = db::connect(&env.db_url).await;
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
-    let discovery_json =
-        reqwest::get("https://accounts.google.com/.well-known/openid-configuration")
-            .await?
-            .json::<Value>()
-            .await?;
+    // `webapi migrate <command>`はマイグレーションだけ行って終了する.
+    // サーバーにだけ必要な設定が無くても実行できるよう、設定の読み込みより先に判定する
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(("migrate", rest)) = args.split_first().map(|(c, rest)| (c.as_str(), rest)) {
+        let (env, settings) = match config::load_migrate() {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprint!("{e}");
+                std::process::exit(1);
+            }
+        };
+        logger::init(&settings.log.level);
+        if let Err(e) =
+            db::run_migrate_command(env.db_url.expose(), env.db_connect_retries, rest).await
+        {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let (env, settings) = match config::load() {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprint!("{e}");
+            std::process::exit(1);
+        }
+    };
+    logger::init(&settings.load().log.level);
+
+    // 型をまたいだエラーコードの重複はderiveでは検出できない
+    let duplicates = system::duplicate_error_codes();
+    if !duplicates.is_empty() {
+        for duplicate in duplicates {
+            eprintln!("{duplicate}");
+        }
+        std::process::exit(1);
+    }
+
+    catch_panic::install_hook();
+    config::spawn_reloader(settings.clone())?;
+
+    system::set_backtrace_capture(env.backtrace_capture);
+    if env.db_migrate_on_startup {
+        db::migrate_with_lock(env.db_url.expose(), env.db_connect_retries).await?;
+    }
+    let db_client = db::DbHandle::connect(&env).await?;
+    let listener = Listener::bind(&env).await?;
+    let metrics = Metrics::new();
+    let http_client = HttpClient::new(&env, metrics.clone())?;
+    let discovery = Discovery::spawn(&env, http_client.clone());
 
+    let error_reporting = env
+        .sentry_dsn
+        .as_ref()
+        .map(|dsn| {
+            SentryReporter::new(
+                http_client.clone(),
+                dsn.expose(),
+                env.sentry_environment.clone(),
+            )
+        })
+        .transpose()?
+        .map(|reporter| {
+            ErrorReporting::new(
+                reporter,
+                env.error_report_sample_rate,
+                Duration::from_secs(env.error_report_dedup_secs),
+            )
+        });
+
+    let shutdown_timeout_secs = env.shutdown_timeout_secs;
     let shared_state = AppState {
         db_client,
         env,
-        discovery_json,
+        settings,
+        discovery,
+        metrics,
+        http_client,
+        error_reporting,
     };
 
-    let router = mk_router(shared_state)
-        .await
-        .into_make_service_with_connect_info::<SocketAddr>();
+    let db_client = shared_state.db_client.clone();
+    let router = mk_router(shared_state).await;
+
+    server::serve(
+        listener,
+        router,
+        server::shutdown_signal(),
+        Duration::from_secs(shutdown_timeout_secs),
+    )
+    .await?;
 
-    axum::serve(listener, router).await?;
+    tracing::info!("DBの接続プールを閉じる");
+    if let Err(e) = db_client.close().await {
+        tracing::warn!("DBの接続プールを閉じられなかった: {e}");
+    }
+    tracing::info!("終了した");
+    std::io::stdout().flush()?;
 
     Ok(())
 }
@@ -55,26 +142,46 @@ async fn mk_router(shared_state: AppState) -> Router {
     Router::new()
         .nest(
             example_route::PATH,
-            example_route::mk_router().route_layer(middleware::from_fn(auth)),
+            example_route::mk_router()
+                .route_layer(middleware::from_fn_with_state(shared_state.clone(), auth)),
         )
         .nest(openid_connect::PATH, openid_connect::mk_router())
-        .route(
-            "/login",
-            routing::get(|| async {
-                use std::fs;
-                let contents = fs::read_to_string("webapi/src/index.html")
-                    .expect("Should have been able to read the file");
-                Html(contents)
-            }),
-        )
-        .layer(TimeoutLayer::new(Duration::from_secs(TIMEOUT_DURATION)))
+        .merge(health::mk_router())
+        .route("/login", routing::get(login_page))
+        .route("/metrics", routing::get(metrics::handler))
+        .layer(middleware::from_fn(db::transaction))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            timeout,
+        ))
+        .layer(middleware::from_fn(catch_panic::catch_panic))
+        .layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            metrics::track,
+        ))
+        .layer(middleware::from_fn(problem::render))
         .layer(middleware::from_fn(log))
-        .layer(middleware::from_fn(setup))
-        .layer(mk_cors_layer())
+        .layer(middleware::from_fn_with_state(
+            shared_state.error_reporting.clone(),
+            error_report::report,
+        ))
+        .layer(middleware::from_fn_with_state(shared_state.clone(), setup))
+        .layer(mk_cors_layer(shared_state.settings.clone()))
         .with_state(shared_state)
 }
 
-async fn setup(mut req: extract::Request, next: middleware::Next) -> Result<Response, StatusCode> {
+async fn login_page(ctx: ReqScopedState) -> Html<String> {
+    use std::fs;
+    let contents = fs::read_to_string("webapi/src/index.html")
+        .expect("Should have been able to read the file");
+    Html(i18n::render_template(ctx.locale, &contents))
+}
+
+async fn setup(
+    extract::State(state): extract::State<AppState>,
+    mut req: extract::Request,
+    next: middleware::Next,
+) -> Result<Response, StatusCode> {
     // CookieJar => クッキー缶　=> クッキーがいっぱい入っている => 他言語だとCookiesみたいなやつ
     let jar = CookieJar::from_headers(req.headers());
     let req_id: Ulid = Ulid::new();
@@ -85,19 +192,27 @@ async fn setup(mut req: extract::Request, next: middleware::Next) -> Result<Resp
         .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?
         .0;
 
-    let req_scoped_state = ReqScopedState::new(req_id);
+    let req_scoped_state = ReqScopedState::new(req_id, req.headers());
+    let request_id = HeaderValue::from_str(&req_scoped_state.request_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let logger = Logger::new(&req_scoped_state, &req, remote_addr);
 
     if let Some(session_id) = jar.get(SESSION_ID_KEY).map(|c| c.value()) {
-        if let Some(session) = framework::session::find_session(session_id).await {
+        let session = framework::session::find_session(session_id).await;
+        state.metrics.session_lookup(session.is_some());
+        if let Some(session) = session {
             req.extensions_mut().insert(session);
         }
     }
 
+    let span = logger.span().clone();
     req.extensions_mut().insert(req_scoped_state);
     req.extensions_mut().insert(logger);
 
-    Ok(next.run(req).await)
+    let mut res = next.run(req).instrument(span).await;
+    res.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+
+    Ok(res)
 }
 
 async fn log(req: extract::Request, next: middleware::Next) -> Result<Response, StatusCode> {
@@ -114,9 +229,16 @@ async fn log(req: extract::Request, next: middleware::Next) -> Result<Response,
     Ok(r)
 }
 
-async fn auth(req: extract::Request, next: middleware::Next) -> Result<Response, StatusCode> {
+async fn auth(
+    extract::State(state): extract::State<AppState>,
+    req: extract::Request,
+    next: middleware::Next,
+) -> Result<Response, StatusCode> {
     if let Some(session) = req.extensions().get::<Session>() {
-        let c = mk_cookie(session.session_id.to_string());
+        let c = mk_cookie(
+            session.session_id.to_string(),
+            &state.settings.load().session,
+        );
         let jar = CookieJar::from_headers(req.headers()).add(c);
         Ok((jar, next.run(req).await).into_response())
     } else {
@@ -124,10 +246,27 @@ async fn auth(req: extract::Request, next: middleware::Next) -> Result<Response,
     }
 }
 
-fn mk_cors_layer() -> CorsLayer {
-    CorsLayer::new().allow_origin(CORS_ALLOWED_ORIGINS.map(|origin| {
-        origin
-            .parse::<HeaderValue>()
-            .expect("originをパースできる必要がある")
+/// 許可するoriginは設定の再読み込みに追従する.
+fn mk_cors_layer(settings: Reloadable<Settings>) -> CorsLayer {
+    CorsLayer::new().allow_origin(AllowOrigin::predicate(move |origin, _| {
+        settings
+            .load()
+            .cors
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.as_bytes() == origin.as_bytes())
     }))
 }
+
+/// リクエスト全体のタイムアウト. 時間は設定の再読み込みに追従する.
+async fn timeout(
+    extract::State(state): extract::State<AppState>,
+    req: extract::Request,
+    next: middleware::Next,
+) -> Response {
+    let duration = Duration::from_secs(state.settings.load().http.timeout_secs);
+    match tokio::time::timeout(duration, next.run(req)).await {
+        Ok(res) => res,
+        Err(_) => StatusCode::REQUEST_TIMEOUT.into_response(),
+    }
+}
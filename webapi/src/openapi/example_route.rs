@@ -1,11 +1,15 @@
 use crate::framework::AppState;
 use axum::{routing, Router};
 
+mod note;
 mod root;
 
 /// パス
 pub const PATH: &str = "/example";
 
 pub fn mk_router() -> Router<AppState> {
-    Router::new().route(root::PATH, routing::get(root::handler))
+    Router::new()
+        .route(root::PATH, routing::get(root::handler))
+        .route(note::PATH, routing::post(note::create))
+        .route(note::ITEM_PATH, routing::get(note::get).put(note::update))
 }
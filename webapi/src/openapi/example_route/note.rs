@@ -0,0 +1,131 @@
+use crate::{
+    db::{example_notes, Db, Tx},
+    framework::{
+        i18n::MessageKey,
+        logger::Logger,
+        system::{AppError, IntoAppError, Panic},
+        versioning::{self, IfMatch, Versioned},
+        Meta, NoneOfProps, ReqScopedState,
+    },
+};
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sea_orm::{ActiveModelTrait, DbErr, EntityTrait, Set};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+/// パス
+pub const PATH: &str = "/notes";
+pub const ITEM_PATH: &str = "/notes/:id";
+
+impl Versioned for example_notes::Entity {
+    fn version_column() -> Self::Column {
+        example_notes::Column::Version
+    }
+}
+
+pub async fn create(
+    tx: Tx,
+    ctx: ReqScopedState,
+    logger: Logger,
+    Json(req): Json<RequestValue>,
+) -> Result<Response, AppError> {
+    let unexpected = |e: DbErr| Panic::from_error(&e).into_app_error(logger.clone(), &ctx.req_id);
+
+    let mut note = example_notes::ActiveModel {
+        id: Set(Ulid::new().to_string()),
+        body: Set(req.body),
+        ..Default::default()
+    };
+    versioning::init_version(&mut note);
+    let note = note.insert(tx.begin().await?).await.map_err(unexpected)?;
+
+    let res = respond(&note, &logger, &ctx)?;
+    Ok((StatusCode::CREATED, res).into_response())
+}
+
+pub async fn get(
+    Path(id): Path<String>,
+    db: Db,
+    ctx: ReqScopedState,
+    logger: Logger,
+) -> Result<Response, AppError> {
+    let note = example_notes::Entity::find_by_id(id)
+        .one(db.read())
+        .await
+        .map_err(|e| Panic::from_error(&e).into_app_error(logger.clone(), &ctx.req_id))?
+        .ok_or_else(not_found)?;
+
+    respond(&note, &logger, &ctx)
+}
+
+/// `If-Match`のバージョンから変わっていなければ更新する.
+pub async fn update(
+    Path(id): Path<String>,
+    if_match: IfMatch,
+    tx: Tx,
+    ctx: ReqScopedState,
+    logger: Logger,
+    Json(req): Json<RequestValue>,
+) -> Result<Response, AppError> {
+    let unexpected = |e: Panic| e.into_app_error(logger.clone(), &ctx.req_id);
+    let txn = tx.begin().await?;
+
+    let note = example_notes::Entity::find_by_id(id)
+        .one(txn)
+        .await
+        .map_err(|e| unexpected(Panic::from_error(&e)))?
+        .ok_or_else(not_found)?;
+    if_match.check(versioning::version_of::<example_notes::Entity>(&note).map_err(unexpected)?)?;
+
+    let mut note: example_notes::ActiveModel = note.into();
+    note.body = Set(req.body);
+    let note = versioning::update(txn, note, if_match.0)
+        .await
+        .map_err(|e| e.into_app_error(logger.clone(), &ctx.req_id))?;
+
+    respond(&note, &logger, &ctx)
+}
+
+/// 本文をJSONで、バージョンを`ETag`で返す.
+fn respond(
+    note: &example_notes::Model,
+    logger: &Logger,
+    ctx: &ReqScopedState,
+) -> Result<Response, AppError> {
+    let version_tag = versioning::version_of::<example_notes::Entity>(note)
+        .map_err(|e| e.into_app_error(logger.clone(), &ctx.req_id))?;
+    let data = ResponseValue {
+        id: note.id.clone(),
+        body: note.body.clone(),
+    };
+
+    Ok(Meta {
+        data: &data,
+        version_tag,
+        custom_props: NoneOfProps,
+    }
+    .into_response())
+}
+
+fn not_found() -> AppError {
+    AppError::WorkflowException(
+        StatusCode::NOT_FOUND,
+        MessageKey::new("error.example.note_not_found"),
+    )
+}
+
+#[derive(Deserialize)]
+pub struct RequestValue {
+    body: String,
+}
+
+#[derive(Serialize)]
+pub struct ResponseValue {
+    id: String,
+    body: String,
+}
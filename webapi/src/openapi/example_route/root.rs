@@ -1,20 +1,14 @@
 use crate::framework::{
     logger::{Logger, LoggerInterface},
     session::Session,
-    AppState, ReqScopedState,
 };
-use axum::{extract::State, Json};
+use axum::Json;
 use serde::Serialize;
 
 /// パス
-pub const PATH: &'static str = "/";
+pub const PATH: &str = "/";
 
-pub async fn handler(
-    State(state): State<AppState>,
-    ctx: ReqScopedState,
-    Session { user, .. }: Session,
-    logger: Logger,
-) -> Json<ResponseValue> {
+pub async fn handler(Session { user, .. }: Session, logger: Logger) -> Json<ResponseValue> {
     logger.info("hello world");
 
     let resp_value = ResponseValue {
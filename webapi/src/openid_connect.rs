@@ -1,23 +1,28 @@
 use crate::{
     framework::{
-        logger::Logger,
+        discovery::Provider,
+        i18n::MessageKey,
+        logger::{Logger, LoggerInterface},
         session::mk_cookie,
-        system::{AppError, IntoAppError, Panic},
+        system::{AppError, IntoAppError},
         AppState, ReqScopedState,
     },
     settings::OPENID_CONNECT_STATE_KEY,
 };
 use axum::{
     extract::{self, Query},
+    http::StatusCode,
     response::{IntoResponse, Redirect, Response},
 };
 use axum::{routing, Router};
 use axum_extra::extract::{cookie::Cookie, CookieJar};
-use jsonwebtoken::{decode, jwk::JwkSet, DecodingKey, Validation};
+use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use token::TokenResponse;
 use ulid::Ulid;
 
+pub mod token;
+
 /// パス
 pub const PATH: &str = "/openid-connect";
 
@@ -29,19 +34,9 @@ pub fn mk_router() -> Router<AppState> {
 
 async fn handler(
     extract::State(state): extract::State<AppState>,
-    ctx: ReqScopedState,
     jar: CookieJar,
-    logger: Logger,
 ) -> Result<Response, AppError> {
-    let authorization_endpoint = state
-        .discovery_json
-        .get("authorization_endpoint")
-        .and_then(|v| v.as_str())
-        .ok_or(
-            Panic::new("authorization_endpointが見つからない".to_string())
-                .into_app_error(logger.clone(), &ctx.req_id),
-        )?;
-
+    let provider = state.discovery.get().ok_or_else(unavailable)?;
     let csrf_token = ulid::Ulid::new().to_string();
     let nonce = ulid::Ulid::new().to_string();
 
@@ -55,8 +50,10 @@ async fn handler(
         ("access_type", "offline"),
     ];
 
-    let client_redirct_url = reqwest::Url::parse_with_params(authorization_endpoint, &query_params)
-        .map_err(|e| Panic::new(e).into_app_error(logger.clone(), &ctx.req_id))?;
+    let mut client_redirct_url = provider.metadata.authorization_endpoint.clone();
+    client_redirct_url
+        .query_pairs_mut()
+        .extend_pairs(&query_params);
 
     let redirect = Redirect::to(client_redirct_url.as_str());
 
@@ -81,18 +78,36 @@ async fn callback_handler(
     ctx: ReqScopedState,
     logger: Logger,
 ) -> Result<Response, AppError> {
-    validate_state_hash(&params.state, &jar)?;
+    let login_failed = |reason| {
+        let metrics = app_state.metrics.clone();
+        move |_: &AppError| metrics.login_failed(reason)
+    };
+
+    validate_state_hash(&params.state, &jar).inspect_err(login_failed("invalid_state"))?;
 
-    let tokens = get_tokens(&params.code, &app_state, &ctx, &logger).await?;
-    let valid_id_token = extract_id_token(&tokens, &app_state, &ctx, &logger).await?;
+    let provider = app_state.discovery.get().ok_or_else(unavailable)?;
+    let tokens = token::exchange_code(
+        &params.code,
+        &provider.metadata.token_endpoint,
+        &app_state.env,
+        &app_state.http_client,
+        &ctx,
+    )
+    .await
+    .map_err(|e| e.into_app_error(logger.clone(), &ctx.req_id))
+    .inspect_err(login_failed("token_request"))?;
+    let valid_id_token = extract_id_token(&tokens, &app_state)
+        .await
+        .inspect_err(login_failed("invalid_id_token"))?;
+    app_state.metrics.login_succeeded();
 
-    println!(
+    logger.info(&format!(
         "login: {}; email: {}",
         &valid_id_token.name, &valid_id_token.email
-    );
+    ));
 
     let response = (
-        add_session_id(remove_state_hash(jar)),
+        add_session_id(remove_state_hash(jar), &app_state),
         Redirect::to("/login"),
     );
 
@@ -103,107 +118,87 @@ fn validate_state_hash(state: &str, jar: &CookieJar) -> Result<(), AppError> {
     let state_in_cookie =
         jar.get(OPENID_CONNECT_STATE_KEY)
             .map(|c| c.value())
-            .ok_or(AppError::AutorizationError(
-                "state値がcookieに含まれていない".to_string(),
-            ))?;
+            .ok_or(AppError::AutorizationError(MessageKey::new(
+                "error.oidc.state_missing",
+            )))?;
 
     if state != state_in_cookie {
-        return Err(AppError::AutorizationError("不正なstate値".to_string()));
+        return Err(AppError::AutorizationError(MessageKey::new(
+            "error.oidc.state_mismatch",
+        )));
     }
 
     Ok(())
 }
 
-async fn get_tokens(
-    code: &str,
-    app_state: &AppState,
-    ctx: &ReqScopedState,
-    logger: &Logger,
-) -> Result<Value, AppError> {
-    let token_endpoint = app_state
-        .discovery_json
-        .get("token_endpoint")
-        .and_then(|v| v.as_str())
-        .ok_or(
-            Panic::new("token_endpointが見つからない".to_string())
-                .into_app_error(logger.clone(), &ctx.req_id),
-        )?;
-
-    let body = json!({
-        "code": code,
-        "client_id": &app_state.env.google_client_id,
-        "client_secret": &app_state.env.google_client_secret,
-        "redirect_uri": &app_state.env.google_redirect_uri,
-        "grant_type": "authorization_code"
-    });
-
-    let error_response = |e| Panic::new(e).into_app_error(logger.clone(), &ctx.req_id);
-
-    reqwest::Client::new()
-        .post(token_endpoint)
-        .body(body.to_string())
-        .send()
-        .await
-        .map_err(error_response)?
-        .json::<Value>()
-        .await
-        .map_err(error_response)
-}
-
 async fn extract_id_token(
-    tokens: &Value,
+    tokens: &TokenResponse,
     app_state: &AppState,
-    ctx: &ReqScopedState,
-    logger: &Logger,
 ) -> Result<Claims, AppError> {
-    let error_response =
-        |e: &str| Panic::new(e.to_string()).into_app_error(logger.clone(), &ctx.req_id);
-
-    let id_token = tokens
-        .get("id_token")
-        .and_then(|v| v.as_str())
-        .ok_or(error_response("token_endpointが見つからない"))?;
-
-    let jwks_uri = app_state
-        .discovery_json
-        .get("jwks_uri")
-        .and_then(|v| v.as_str())
-        .ok_or(error_response("jwks_uriが見つからない"))?;
+    let id_token = tokens.id_token.expose();
+    let mut provider = app_state.discovery.get().ok_or_else(unavailable)?;
 
-    let error_response = |e| Panic::new(e).into_app_error(logger.clone(), &ctx.req_id);
+    // 鍵がローテーションされた直後は、新しい鍵がまだ手元のJWKSに無い
+    let kid = decode_header(id_token).ok().and_then(|header| header.kid);
+    if kid.is_some_and(|kid| provider.jwk_set.find(&kid).is_none()) {
+        if let Some(refreshed) = app_state.discovery.refresh_for_unknown_kid().await {
+            provider = refreshed;
+        }
+    }
 
-    let jwk_set = reqwest::get(jwks_uri)
-        .await
-        .map_err(error_response)?
-        .json::<JwkSet>()
-        .await
-        .map_err(error_response)?;
+    verify_id_token(id_token, &provider, &app_state.env.google_client_id)
+}
 
+/// IDトークンの署名、発行者、対象者、有効期限を検証する.
+fn verify_id_token(
+    id_token: &str,
+    provider: &Provider,
+    client_id: &str,
+) -> Result<Claims, AppError> {
+    // 署名方式はdiscovery documentで公開されているものだけを受け付ける
+    let header = decode_header(id_token).map_err(|_| AppError::AuthenticationError)?;
+    if !provider
+        .metadata
+        .id_token_signing_alg_values_supported
+        .contains(&header.alg)
+    {
+        return Err(AppError::AuthenticationError);
+    }
     let validation = {
-        let mut tmp = Validation::new(jsonwebtoken::Algorithm::RS256);
-        tmp.set_audience(&[&app_state.env.google_client_id]);
+        let mut tmp = Validation::new(header.alg);
+        tmp.set_audience(&[client_id]);
+        tmp.set_issuer(&[&provider.metadata.issuer]);
         tmp
     };
 
-    let decode_claims = |jwk| {
-        decode::<Claims>(
-            id_token,
-            &DecodingKey::from_jwk(jwk).expect("base64decodeできること"),
-            &validation,
-        )
-    };
+    // kidが無い場合は鍵が1つだけの時に限りその鍵を使う
+    let keys = &provider.jwk_set.keys;
+    let jwk = match &header.kid {
+        Some(kid) => provider.jwk_set.find(kid),
+        None if keys.len() == 1 => keys.first(),
+        None => None,
+    }
+    .ok_or(AppError::AuthenticationError)?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| AppError::AuthenticationError)?;
+
+    decode::<Claims>(id_token, &key, &validation)
+        .map(|data| data.claims)
+        .map_err(|_| AppError::AuthenticationError)
+}
 
-    jwk_set
-        .keys
-        .iter()
-        .map(decode_claims)
-        .find_map(|item| item.ok())
-        .ok_or(AppError::AuthenticationError)
-        .map(|item| item.claims)
+/// discovery documentをまだ取得できていない場合.
+fn unavailable() -> AppError {
+    AppError::WorkflowException(
+        StatusCode::SERVICE_UNAVAILABLE,
+        MessageKey::new("error.oidc.unavailable"),
+    )
 }
 
-fn add_session_id(jar: CookieJar) -> CookieJar {
-    jar.add(mk_cookie(Ulid::new().to_string()))
+fn add_session_id(jar: CookieJar, app_state: &AppState) -> CookieJar {
+    jar.add(mk_cookie(
+        Ulid::new().to_string(),
+        &app_state.settings.load().session,
+    ))
 }
 
 fn remove_state_hash(jar: CookieJar) -> CookieJar {
@@ -228,3 +223,106 @@ pub struct Claims {
     picture: String,
     sub: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framework::discovery::ProviderMetadata;
+    use jsonwebtoken::{encode, jwk::JwkSet, Algorithm, EncodingKey, Header};
+
+    const CLIENT_ID: &str = "client";
+    const ISSUER: &str = "https://accounts.google.com";
+
+    fn provider() -> Provider {
+        let document = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/config/oidc/google.json"
+        ));
+        let jwks = include_str!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/oidc_jwks.json"
+        ));
+        Provider {
+            metadata: ProviderMetadata::from_document(serde_json::from_str(document).unwrap())
+                .unwrap(),
+            jwk_set: serde_json::from_str::<JwkSet>(jwks).unwrap(),
+            fetched_at: chrono::Utc::now(),
+        }
+    }
+
+    fn claims(iss: &str) -> Claims {
+        let now = chrono::Utc::now().timestamp();
+        Claims {
+            aud: CLIENT_ID.to_string(),
+            email: "user@example.com".to_string(),
+            email_verified: true,
+            exp: now + 60,
+            family_name: None,
+            given_name: None,
+            iat: now,
+            iss: iss.to_string(),
+            name: "user".to_string(),
+            picture: String::new(),
+            sub: "1".to_string(),
+        }
+    }
+
+    fn sign(header: Header, claims: &Claims) -> String {
+        let pem = include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/oidc_signing_key.pem"
+        ));
+        encode(&header, claims, &EncodingKey::from_rsa_pem(pem).unwrap()).unwrap()
+    }
+
+    fn rs256() -> Header {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test-key".to_string());
+        header
+    }
+
+    #[test]
+    fn accepts_tokens_from_the_provider() {
+        let claims = claims(ISSUER);
+        let token = sign(rs256(), &claims);
+        let verified = verify_id_token(&token, &provider(), CLIENT_ID).unwrap();
+        assert_eq!(verified, claims);
+    }
+
+    #[test]
+    fn selects_the_key_by_kid() {
+        let mut header = rs256();
+        header.kid = Some("unknown".to_string());
+        let token = sign(header, &claims(ISSUER));
+        assert!(matches!(
+            verify_id_token(&token, &provider(), CLIENT_ID),
+            Err(AppError::AuthenticationError)
+        ));
+
+        // 鍵が1つだけならkidは省略できる
+        let mut header = rs256();
+        header.kid = None;
+        let token = sign(header, &claims(ISSUER));
+        assert!(verify_id_token(&token, &provider(), CLIENT_ID).is_ok());
+    }
+
+    #[test]
+    fn rejects_other_issuers() {
+        let token = sign(rs256(), &claims("https://evil.example.com"));
+        assert!(matches!(
+            verify_id_token(&token, &provider(), CLIENT_ID),
+            Err(AppError::AuthenticationError)
+        ));
+    }
+
+    #[test]
+    fn rejects_algorithms_the_provider_does_not_publish() {
+        let mut header = rs256();
+        header.alg = Algorithm::RS512;
+        let token = sign(header, &claims(ISSUER));
+        assert!(matches!(
+            verify_id_token(&token, &provider(), CLIENT_ID),
+            Err(AppError::AuthenticationError)
+        ));
+    }
+}
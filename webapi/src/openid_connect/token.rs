@@ -0,0 +1,200 @@
+use std::fmt;
+
+use chrono::Utc;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::framework::{
+    env::{ClientAuthMethod, Env},
+    http_client::HttpClient,
+    secret::Secret,
+    system::{DomainError, Panic},
+    ReqScopedState,
+};
+
+/// `private_key_jwt`のclient_assertionの有効期間
+const CLIENT_ASSERTION_LIFETIME_SECS: i64 = 60;
+
+/// token endpointの成功レスポンス.
+#[derive(Debug, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: Secret<String>,
+    pub token_type: String,
+    pub expires_in: Option<u64>,
+    pub refresh_token: Option<Secret<String>>,
+    pub scope: Option<String>,
+    /// OpenID Connectでは必須
+    pub id_token: Secret<String>,
+}
+
+/// token endpointのエラーレスポンス. RFC 6749 5.2.
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// token endpointへのリクエストの失敗.
+#[derive(Debug, DomainError)]
+pub enum TokenError {
+    /// 認可コードの期限切れや再利用. ログインをやり直せば解決する.
+    #[domain_error(code = "oidc.invalid_grant", status = 400)]
+    InvalidGrant(Option<String>),
+    /// クライアントの認証情報が誤っている. 設定の問題.
+    #[domain_error(code = "oidc.invalid_client", status = 502)]
+    InvalidClient(Option<String>),
+    /// その他のOAuthのエラー
+    #[domain_error(code = "oidc.token_error", status = 502)]
+    Rejected {
+        error: String,
+        description: Option<String>,
+    },
+    #[domain_error(panic)]
+    Unexpected(Panic),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (error, description) = match self {
+            TokenError::InvalidGrant(description) => ("invalid_grant", description),
+            TokenError::InvalidClient(description) => ("invalid_client", description),
+            TokenError::Rejected { error, description } => (error.as_str(), description),
+            // 詳細はログとエラー報告に出る
+            TokenError::Unexpected(_) => return f.write_str("unexpected"),
+        };
+        match description {
+            Some(description) => write!(f, "{error}: {description}"),
+            None => write!(f, "{error}"),
+        }
+    }
+}
+
+impl From<ErrorResponse> for TokenError {
+    fn from(res: ErrorResponse) -> Self {
+        match res.error.as_str() {
+            "invalid_grant" => TokenError::InvalidGrant(res.error_description),
+            "invalid_client" | "unauthorized_client" => {
+                TokenError::InvalidClient(res.error_description)
+            }
+            _ => TokenError::Rejected {
+                error: res.error,
+                description: res.error_description,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ClientAssertionClaims<'a> {
+    iss: &'a str,
+    sub: &'a str,
+    aud: &'a str,
+    jti: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// 認可コードをトークンに交換する. 本文は`application/x-www-form-urlencoded`で送る.
+pub async fn exchange_code(
+    code: &str,
+    token_endpoint: &Url,
+    env: &Env,
+    client: &HttpClient,
+    ctx: &ReqScopedState,
+) -> Result<TokenResponse, TokenError> {
+    let client_id = env.google_client_id.as_str();
+    let mut form = vec![
+        ("grant_type", "authorization_code".to_string()),
+        ("code", code.to_string()),
+        ("redirect_uri", env.google_redirect_uri.clone()),
+    ];
+
+    let mut builder = client.post(token_endpoint.clone());
+    match env.oidc_client_auth_method {
+        ClientAuthMethod::ClientSecretBasic => {
+            // RFC 6749 2.3.1. IDとシークレットはフォームエンコードしてからBasic認証にする
+            builder = builder.basic_auth(
+                form_encode(client_id),
+                Some(form_encode(env.google_client_secret.expose())),
+            );
+        }
+        ClientAuthMethod::ClientSecretPost => {
+            form.push(("client_id", client_id.to_string()));
+            form.push((
+                "client_secret",
+                env.google_client_secret.expose().to_string(),
+            ));
+        }
+        ClientAuthMethod::PrivateKeyJwt => {
+            let assertion = client_assertion(client_id, token_endpoint, env)?;
+            form.push(("client_id", client_id.to_string()));
+            form.push((
+                "client_assertion_type",
+                "urn:ietf:params:oauth:client-assertion-type:jwt-bearer".to_string(),
+            ));
+            form.push(("client_assertion", assertion));
+        }
+    }
+
+    let res = client
+        .send(builder.form(&form), Some(ctx))
+        .await
+        .map_err(|e| TokenError::from_panic(Panic::from_error(&e)))?;
+    let status = res.status();
+    let body = res
+        .bytes()
+        .await
+        .map_err(|e| TokenError::from_panic(Panic::from_error(&e)))?;
+
+    if status.is_success() {
+        return serde_json::from_slice(&body).map_err(|e| {
+            TokenError::from_panic(Panic::new(format!(
+                "token endpointのレスポンスを解析できない: {e}"
+            )))
+        });
+    }
+
+    // エラーレスポンスは400か401で返る. それ以外やJSONでないものは想定外.
+    match serde_json::from_slice::<ErrorResponse>(&body) {
+        Ok(error) if matches!(status, StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED) => {
+            Err(error.into())
+        }
+        _ => Err(TokenError::from_panic(Panic::new(format!(
+            "token endpointが{status}を返した"
+        )))),
+    }
+}
+
+/// `private_key_jwt`のclient_assertion. RFC 7523.
+fn client_assertion(
+    client_id: &str,
+    token_endpoint: &Url,
+    env: &Env,
+) -> Result<String, TokenError> {
+    let key = env
+        .oidc_private_key
+        .as_ref()
+        .ok_or_else(|| TokenError::from_panic(Panic::new("oidc_private_keyが設定されていない")))?;
+    let key = EncodingKey::from_rsa_pem(key.expose().as_bytes())
+        .map_err(|e| TokenError::from_panic(Panic::from_error(&e)))?;
+
+    let mut header = Header::new(jsonwebtoken::Algorithm::RS256);
+    header.kid = env.oidc_private_key_id.clone();
+    let now = Utc::now().timestamp();
+    let claims = ClientAssertionClaims {
+        iss: client_id,
+        sub: client_id,
+        aud: token_endpoint.as_str(),
+        jti: Ulid::new().to_string(),
+        iat: now,
+        exp: now + CLIENT_ASSERTION_LIFETIME_SECS,
+    };
+
+    encode(&header, &claims, &key).map_err(|e| TokenError::from_panic(Panic::from_error(&e)))
+}
+
+fn form_encode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
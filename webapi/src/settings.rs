@@ -1,7 +1,4 @@
 pub const SESSION_ID_KEY: &str = "session-id";
 pub const OPENID_CONNECT_STATE_KEY: &str = "state-key";
-pub const SESSION_EXPIRATION_HOURS: i64 = 2;
-
-pub const CORS_ALLOWED_ORIGINS: [&str; 0] = [];
-
-pub const TIMEOUT_DURATION: u64 = 30;
+/// 表示言語の設定を保持するcookie
+pub const LOCALE_KEY: &str = "lang";
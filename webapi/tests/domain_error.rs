@@ -0,0 +1,11 @@
+//! `#[derive(DomainError)]`がコンパイルエラーにするケース.
+//! エラーメッセージが変わった場合は`TRYBUILD=overwrite cargo test -p webapi --test domain_error`で更新する.
+
+#[test]
+fn domain_error() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/pass.rs");
+    t.compile_fail("tests/ui/duplicate_code.rs");
+    t.compile_fail("tests/ui/reserved_code.rs");
+    t.compile_fail("tests/ui/missing_panic.rs");
+}
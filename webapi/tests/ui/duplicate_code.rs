@@ -0,0 +1,13 @@
+use webapi::framework::system::{DomainError, Panic};
+
+#[derive(Debug, DomainError)]
+enum NoteError {
+    #[domain_error(code = "ui_test.note", status = 400)]
+    Invalid,
+    #[domain_error(code = "ui_test.note", status = 409)]
+    Conflict,
+    #[domain_error(panic)]
+    Unexpected(Panic),
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use webapi::framework::system::DomainError;
+
+#[derive(Debug, DomainError)]
+enum NoteError {
+    #[domain_error(code = "ui_test.note", status = 400)]
+    Invalid,
+}
+
+fn main() {}
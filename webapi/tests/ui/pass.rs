@@ -0,0 +1,31 @@
+use std::fmt;
+
+use webapi::framework::system::{DomainError, ErrorCode, Panic};
+
+#[derive(Debug, DomainError)]
+enum NoteError {
+    #[domain_error(code = "ui_test.note_locked", status = 423)]
+    Locked,
+    #[domain_error(code = "ui_test.note_too_long", status = 400, message_key = "error.ui_test")]
+    TooLong { max: usize },
+    #[domain_error(panic)]
+    Unexpected(Panic),
+}
+
+impl fmt::Display for NoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+fn main() {
+    assert_eq!(
+        NoteError::Locked.error_code(),
+        ErrorCode::new("ui_test.note_locked", 423, "error.ui_test.note_locked")
+    );
+    assert_eq!(NoteError::TooLong { max: 1 }.error_code().message_key, "error.ui_test");
+    assert_eq!(
+        NoteError::from_panic(Panic::new("boom")).error_code(),
+        ErrorCode::UNEXPECTED
+    );
+}
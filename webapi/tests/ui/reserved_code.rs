@@ -0,0 +1,11 @@
+use webapi::framework::system::{DomainError, Panic};
+
+#[derive(Debug, DomainError)]
+enum NoteError {
+    #[domain_error(code = "unexpected_error", status = 500)]
+    Broken,
+    #[domain_error(panic)]
+    Unexpected(Panic),
+}
+
+fn main() {}